@@ -1,10 +1,13 @@
 use bevy::prelude::*;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 
 pub mod compiler;
 use compiler::*;
+pub mod save;
+pub mod sexpr;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub enum BlockType {
     Statement,
     Value,
@@ -62,30 +65,87 @@ pub struct Line {
     pub label: String,
 }
 
+impl BlockList {
+    // idのブロックから辿れるグラフを、ノードごとにブロックのidを付けてAstNodeにする
+    // エラーにはそれを起こしたブロックのidが付く
+    pub fn parse(&self, id: u32) -> Result<AstNode, ParseError> {
+        self.parse_path(id, &mut vec![])
+    }
+
+    // fromから入力を辿ってtoに着くか
+    pub fn reaches(&self, from: u32, to: u32) -> bool {
+        let mut visited: HashSet<u32> = HashSet::new();
+        let mut work = vec![from];
+        while let Some(id) = work.pop() {
+            if id == to {
+                return true;
+            }
+            if !visited.insert(id) {
+                continue;
+            }
+            if let Some((_, block)) = self.item.get(&id) {
+                work.extend(block.inputs.iter());
+            }
+        }
+        false
+    }
+
+    // pathは今辿っている途中のブロック　そこに戻ってくるなら入力が循環している
+    fn parse_path(&self, id: u32, path: &mut Vec<u32>) -> Result<AstNode, ParseError> {
+        if path.contains(&id) {
+            return Err(ParseError::Cycle(id));
+        }
+        match self.item.get(&id) {
+            Some((_, block)) => {
+                path.push(id);
+                let node = block.parse(self, path).map_err(|err| err.at(id));
+                path.pop();
+                Ok(AstNode::Located {
+                    block: id,
+                    node: Box::new(node?),
+                })
+            }
+            None => Err(ParseError::MissingBlock { id, parent: None }),
+        }
+    }
+}
+
 impl Block {
-    pub fn parse(&self, block_list: &BlockList) -> Result<AstNode, String> {
+    fn parse(&self, block_list: &BlockList, path: &mut Vec<u32>) -> Result<AstNode, ParseError> {
         match self.data.block_type {
             BlockType::Statement => {
                 let mut options: Vec<AstNode> = vec![];
                 for exp in self.inputs.clone() {
-                    options.push(block_list.item[&exp].1.parse(block_list)?);
+                    options.push(block_list.parse_path(exp, path)?);
                 }
                 Ok(AstNode::Statement {
                     statement: self.data.text.clone(),
                     options,
                 })
             }
+            BlockType::Value
+                if self.data.text.len() >= 2
+                    && self.data.text.starts_with('"')
+                    && self.data.text.ends_with('"') =>
+            {
+                Ok(AstNode::ValueStr(
+                    self.data.text[1..self.data.text.len() - 1].to_string(),
+                ))
+            }
             BlockType::Value => match self.data.text.parse::<i64>() {
                 Ok(num) => Ok(AstNode::ValueInteger(num)),
                 Err(_) => match self.data.text.parse::<f64>() {
                     Ok(num) => Ok(AstNode::ValueFloat(num)),
-                    Err(_) => Ok(AstNode::ValueStr(self.data.text.clone())),
+                    Err(_) => match self.data.text.parse::<bool>() {
+                        Ok(b) => Ok(AstNode::ValueBool(b)),
+                        Err(_) => Ok(AstNode::ValueStr(self.data.text.clone())),
+                    },
                 },
             },
             BlockType::List => {
                 let mut res: Vec<AstNode> = vec![];
                 for exp in self.inputs.clone() {
-                    res.push(block_list.item[&exp].1.parse(block_list)?);
+                    res.push(block_list.parse_path(exp, path)?);
                 }
                 Ok(AstNode::List {
                     name: self.data.text.clone(),
@@ -96,7 +156,7 @@ impl Block {
                 if self.inputs.len() != 0 {
                     let mut args: Vec<AstNode> = vec![];
                     for exp in self.inputs.clone() {
-                        args.push(block_list.item[&exp].1.parse(block_list)?);
+                        args.push(block_list.parse_path(exp, path)?);
                     }
                     Ok(AstNode::Function {
                         func: self.data.text.clone(),
@@ -109,3 +169,63 @@ impl Block {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block_list(blocks: &[(u32, &str, BlockType, &[u32])]) -> BlockList {
+        let mut block_list = BlockList::default();
+        for (id, text, block_type, inputs) in blocks {
+            let block = Block {
+                data: BlockData {
+                    text: text.to_string(),
+                    block_type: *block_type,
+                },
+                position: Vec2::ZERO,
+                inputs: inputs.to_vec(),
+                comment: "".to_string(),
+            };
+            block_list.item.insert(*id, (Entity::PLACEHOLDER, block));
+        }
+        block_list
+    }
+
+    #[test]
+    fn rejects_a_block_connected_to_itself() {
+        let block_list = block_list(&[(0, "printi", BlockType::Identifier, &[0])]);
+        assert_eq!(block_list.parse(0).err(), Some(ParseError::Cycle(0)));
+    }
+
+    #[test]
+    fn rejects_a_block_connected_to_an_ancestor() {
+        let block_list = block_list(&[
+            (0, "list", BlockType::List, &[1]),
+            (1, "printi", BlockType::Identifier, &[2]),
+            (2, "addi", BlockType::Identifier, &[3, 0]),
+            (3, "1", BlockType::Value, &[]),
+        ]);
+        assert_eq!(block_list.parse(0).err(), Some(ParseError::Cycle(0)));
+    }
+
+    #[test]
+    fn finds_blocks_reachable_through_inputs() {
+        let block_list = block_list(&[
+            (0, "printi", BlockType::Identifier, &[1]),
+            (1, "addi", BlockType::Identifier, &[2, 2]),
+            (2, "3", BlockType::Value, &[]),
+        ]);
+        assert!(block_list.reaches(0, 2));
+        assert!(block_list.reaches(1, 1));
+        assert!(!block_list.reaches(2, 0));
+    }
+
+    #[test]
+    fn accepts_a_block_used_twice() {
+        let block_list = block_list(&[
+            (0, "addi", BlockType::Identifier, &[1, 1]),
+            (1, "3", BlockType::Value, &[]),
+        ]);
+        assert!(block_list.parse(0).is_ok());
+    }
+}
@@ -1,5 +1,20 @@
 use bevy::{color::palettes::css::PERU, prelude::*};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+pub mod asm;
+pub mod disasm;
+pub mod error;
+pub use error::*;
+mod heap;
+use heap::*;
+pub mod infer;
+pub mod program;
+use program::*;
+pub mod types;
+pub use types::*;
+pub mod verify;
 
 #[derive(Clone)]
 pub enum AstNode {
@@ -10,6 +25,7 @@ pub enum AstNode {
     ValueInteger(i64),
     ValueFloat(f64),
     ValueStr(String),
+    ValueBool(bool),
     Function {
         func: String,
         args: Vec<AstNode>,
@@ -19,8 +35,13 @@ pub enum AstNode {
         codes: Vec<AstNode>,
     },
     Identifier(String),
+    Located {
+        block: u32, // このノードを作ったブロックのid
+        node: Box<AstNode>,
+    },
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Opecodes {
     CopySP,      // スタックの指定の場所から８バイトをコピー
     OverWriteSP, // スタックの指定の場所で８バイトを書き換え 先に値をスタックに積んでおく v OverWriteSP p
@@ -50,6 +71,31 @@ pub enum Opecodes {
     ModF,
     OutputF,
     End,
+    EqI,
+    NeI,
+    LtI,
+    GtI,
+    LeI,
+    GeI,
+    EqF,
+    NeF,
+    LtF,
+    GtF,
+    LeF,
+    GeF,
+    And,
+    Or,
+    Not,
+    PushStr, // 定数テーブルの文字列をヒープに確保して積む PushStr index
+    OutputS,
+    ConcatS,
+    Length, // 文字列または配列の長さ
+    SubstrS,
+    EqS,
+    MakeArray, // 先に要素数と初期値を積んでおく
+    GetA,
+    SetA,
+    PushA,
 }
 
 impl TryFrom<u8> for Opecodes {
@@ -85,59 +131,228 @@ impl TryFrom<u8> for Opecodes {
             0x19 => Ok(Opecodes::ModF),
             0x1A => Ok(Opecodes::OutputF),
             0x1B => Ok(Opecodes::End),
+            0x1C => Ok(Opecodes::EqI),
+            0x1D => Ok(Opecodes::NeI),
+            0x1E => Ok(Opecodes::LtI),
+            0x1F => Ok(Opecodes::GtI),
+            0x20 => Ok(Opecodes::LeI),
+            0x21 => Ok(Opecodes::GeI),
+            0x22 => Ok(Opecodes::EqF),
+            0x23 => Ok(Opecodes::NeF),
+            0x24 => Ok(Opecodes::LtF),
+            0x25 => Ok(Opecodes::GtF),
+            0x26 => Ok(Opecodes::LeF),
+            0x27 => Ok(Opecodes::GeF),
+            0x28 => Ok(Opecodes::And),
+            0x29 => Ok(Opecodes::Or),
+            0x2A => Ok(Opecodes::Not),
+            0x2B => Ok(Opecodes::PushStr),
+            0x2C => Ok(Opecodes::OutputS),
+            0x2D => Ok(Opecodes::ConcatS),
+            0x2E => Ok(Opecodes::Length),
+            0x2F => Ok(Opecodes::SubstrS),
+            0x30 => Ok(Opecodes::EqS),
+            0x31 => Ok(Opecodes::MakeArray),
+            0x32 => Ok(Opecodes::GetA),
+            0x33 => Ok(Opecodes::SetA),
+            0x34 => Ok(Opecodes::PushA),
             _ => Err(()), // 無効な値はエラーを返す
         }
     }
 }
 
+impl Opecodes {
+    // 命令の直後に続くオペランドのバイト数
+    pub fn operand_size(&self) -> usize {
+        match self {
+            Opecodes::CopySP | Opecodes::OverWriteSP | Opecodes::PushS64 | Opecodes::PushStr => 8,
+            _ => 0,
+        }
+    }
+
+    // アセンブラと逆アセンブラで使う名前
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Opecodes::CopySP => "copysp",
+            Opecodes::OverWriteSP => "overwritesp",
+            Opecodes::SaveR => "saver",
+            Opecodes::PushS32 => "push.s32",
+            Opecodes::PushS64 => "push.s64",
+            Opecodes::PopS32 => "pop.s32",
+            Opecodes::PopS64 => "pop.s64",
+            Opecodes::AddI => "add.i",
+            Opecodes::SubI => "sub.i",
+            Opecodes::MulI => "mul.i",
+            Opecodes::DivI => "div.i",
+            Opecodes::ModI => "mod.i",
+            Opecodes::OutputI => "output.i",
+            Opecodes::Jump => "jump",
+            Opecodes::SetFP => "setfp",
+            Opecodes::SetRET => "setret",
+            Opecodes::ResetFP => "resetfp",
+            Opecodes::PushRET => "pushret",
+            Opecodes::IfNotJump => "ifnotjump",
+            Opecodes::ExportFP => "exportfp",
+            Opecodes::PushFP => "pushfp",
+            Opecodes::AddF => "add.f",
+            Opecodes::SubF => "sub.f",
+            Opecodes::MulF => "mul.f",
+            Opecodes::DivF => "div.f",
+            Opecodes::ModF => "mod.f",
+            Opecodes::OutputF => "output.f",
+            Opecodes::End => "end",
+            Opecodes::EqI => "eq.i",
+            Opecodes::NeI => "ne.i",
+            Opecodes::LtI => "lt.i",
+            Opecodes::GtI => "gt.i",
+            Opecodes::LeI => "le.i",
+            Opecodes::GeI => "ge.i",
+            Opecodes::EqF => "eq.f",
+            Opecodes::NeF => "ne.f",
+            Opecodes::LtF => "lt.f",
+            Opecodes::GtF => "gt.f",
+            Opecodes::LeF => "le.f",
+            Opecodes::GeF => "ge.f",
+            Opecodes::And => "and",
+            Opecodes::Or => "or",
+            Opecodes::Not => "not",
+            Opecodes::PushStr => "push.str",
+            Opecodes::OutputS => "output.s",
+            Opecodes::ConcatS => "concat.s",
+            Opecodes::Length => "length",
+            Opecodes::SubstrS => "substr.s",
+            Opecodes::EqS => "eq.s",
+            Opecodes::MakeArray => "make.array",
+            Opecodes::GetA => "get.a",
+            Opecodes::SetA => "set.a",
+            Opecodes::PushA => "push.a",
+        }
+    }
+
+    pub fn from_mnemonic(name: &str) -> Option<Self> {
+        (0..=u8::MAX)
+            .filter_map(|byte| Opecodes::try_from(byte).ok())
+            .find(|opcode| opcode.mnemonic() == name)
+    }
+}
+
+// コンパイルしたバイト列とその値の型
+type Compiled = (Vec<u8>, Type);
+
 #[derive(Resource, Default)]
 pub struct Environment {
-    pub stack: Vec<HashMap<String, (i64, String)>>,
+    pub stack: Vec<HashMap<String, (i64, Type)>>,
+    pub depth: i64,             // 現在のフレームでFPより上に積まれている値の数
+    pub constants: Vec<String>, // 文字列の定数テーブル
+    pub source_map: Vec<SourceRange>,
+    pub diagnostics: Vec<CompileError>, // コンパイルを続けられたエラー
 }
 
 impl Environment {
-    pub fn find(&self, name: String) -> Result<(i64, String), String> {
+    pub fn find(&self, name: String) -> Result<(i64, Type), CompileErrorKind> {
         for i in (0..self.stack.len()).rev() {
             if let Some(res) = self.stack[i].get(&name) {
                 return Ok(res.clone());
             }
         }
-        Err(format!("variable '{}' is not defined.", name))
+        Err(CompileErrorKind::UndefinedVariable(name))
+    }
+    // 見つからなければエラーを記録して、何の型にも合う変数として続ける
+    pub fn find_or_report(&mut self, name: String) -> (i64, Type) {
+        self.find(name).unwrap_or_else(|err| {
+            self.report(err);
+            (0, Type::Unknown)
+        })
     }
-    pub fn set_type(&mut self, name: String, type_name: String) -> Result<(), String> {
+    // 型の食い違いのように、記録してコンパイルを続けられるエラー
+    pub fn report(&mut self, err: impl Into<CompileError>) {
+        self.diagnostics.push(err.into());
+    }
+    pub fn set_type(&mut self, name: String, t: Type) -> Result<(), CompileErrorKind> {
         for i in (0..self.stack.len()).rev() {
             if let Some(res) = self.stack[i].get_mut(&name) {
-                res.1 = type_name.clone();
+                res.1 = t;
                 return Ok(());
             }
         }
-        Err(format!("variable '{}' is not defined.", name))
+        Err(CompileErrorKind::UndefinedVariable(name))
     }
 }
 
-fn check_type(t1: String, t2: String) -> bool {
-    if t1 == "".to_string() {
-        return true;
+// Unknownはどの型とも合う　配列や関数の中にあっても同じ
+fn check_type(t1: &Type, t2: &Type) -> bool {
+    match (t1, t2) {
+        (Type::Unknown | Type::Var(_), _) | (_, Type::Unknown | Type::Var(_)) => true,
+        (Type::Array(e1), Type::Array(e2)) => check_type(e1, e2),
+        (Type::Function(params1, ret1), Type::Function(params2, ret2)) => {
+            params1.len() == params2.len()
+                && params1
+                    .iter()
+                    .zip(params2)
+                    .all(|(p1, p2)| check_type(p1, p2))
+                && check_type(ret1, ret2)
+        }
+        _ => t1 == t2,
+    }
+}
+
+fn array_type(element_type: &Type) -> Type {
+    Type::Array(Box::new(element_type.clone()))
+}
+
+// identifier_listの要素の"n:integer"のような型注釈を名前と型に分ける　注釈がなければUnknown
+// "->integer"はlambdaの戻り値の型の注釈で、名前は空になる
+pub fn split_annotation(text: &str) -> Result<(&str, Type), CompileErrorKind> {
+    let (name, annotation) = match text.strip_prefix("->") {
+        Some(annotation) => ("", annotation),
+        None => match text.split_once(':') {
+            Some(pair) => pair,
+            None => return Ok((text, Type::Unknown)),
+        },
+    };
+    match Type::parse(annotation) {
+        Some(t) => Ok((name, t)),
+        None => Err(CompileErrorKind::UnknownType(annotation.to_string())),
+    }
+}
+
+// 配列の要素の型 配列でなければNone 型が分からないときはUnknown
+fn element_type(t: &Type) -> Option<Type> {
+    match t {
+        Type::Unknown => Some(Type::Unknown),
+        Type::Array(element) => Some(*element.clone()),
+        _ => None,
     }
-    t1 == t2
 }
 
 impl AstNode {
+    // ブロックのidを外した中身
+    pub fn inner(&self) -> &AstNode {
+        match self {
+            AstNode::Located { node, .. } => node.inner(),
+            _ => self,
+        }
+    }
+
     pub fn compile(
         &self,
         environment: &mut Environment,
         currentpos_reset: bool,
-    ) -> Result<(Vec<u8>, String), String> {
+    ) -> Result<Compiled, CompileError> {
         static mut CURRENT_POS: u32 = 0;
 
         if currentpos_reset {
             unsafe {
                 CURRENT_POS = 0;
             }
+            environment.depth = 0;
+            environment.constants.clear();
+            environment.source_map.clear();
+            environment.diagnostics.clear();
         }
 
         let mut res: Vec<u8> = vec![];
-        let mut return_type: String = "".to_string();
+        let mut return_type: Type = Type::Unknown;
 
         fn add_u8(vec: &mut Vec<u8>, n: u8) {
             vec.push(n);
@@ -183,52 +398,84 @@ impl AstNode {
         }
         fn get_binop_args(
             args: &Vec<AstNode>,
-            expected_type: String,
+            expected_type: Type,
             environment: &mut Environment,
-        ) -> Result<((Vec<u8>, String), (Vec<u8>, String)), String> {
+        ) -> Result<(Compiled, Compiled), CompileError> {
             if args.len() != 2 {
-                Err(format!(
-                    "this function takes 2 arguments but {} argument was supplied.",
-                    args.len()
-                ))
+                Err(CompileErrorKind::ArityMismatch {
+                    expected: 2,
+                    found: args.len(),
+                }
+                .into())
             } else {
                 let a = args[0].compile(environment, false)?;
-                if !check_type(a.1.clone(), expected_type.clone()) {
-                    Err(format!(
-                        "expected type {}, but found type {}.",
-                        expected_type, a.1
-                    ))
-                } else {
-                    let b = args[1].compile(environment, false)?;
-                    if !check_type(b.1.clone(), expected_type.clone()) {
-                        Err(format!(
-                            "expected type {}, but found type {}.",
-                            expected_type, b.1
-                        ))
-                    } else {
-                        Ok((a, b))
+                environment.depth += 1;
+                let b = args[1].compile(environment, false)?;
+                environment.depth -= 1;
+                for found in [&a.1, &b.1] {
+                    if !check_type(found, &expected_type) {
+                        environment.report(CompileErrorKind::TypeMismatch {
+                            expected: expected_type.to_string(),
+                            found: found.to_string(),
+                        });
                     }
                 }
+                Ok((a, b))
             }
         }
-        fn get_identifier_list(identifier_list: AstNode) -> Result<Vec<String>, String> {
-            match identifier_list {
+        fn compile_args(
+            args: &[AstNode],
+            count: usize,
+            environment: &mut Environment,
+        ) -> Result<Vec<Compiled>, CompileError> {
+            if args.len() != count {
+                return Err(CompileErrorKind::ArityMismatch {
+                    expected: count,
+                    found: args.len(),
+                }
+                .into());
+            }
+            let mut res = vec![];
+            for arg in args.iter() {
+                res.push(arg.compile(environment, false)?);
+                environment.depth += 1;
+            }
+            environment.depth -= count as i64;
+            Ok(res)
+        }
+        // 変数の名前と型注釈の型、戻り値の型注釈（なければUnknown）
+        fn get_identifier_list(
+            identifier_list: AstNode,
+        ) -> Result<(Vec<(String, Type)>, Type), CompileError> {
+            match identifier_list.inner().clone() {
                 AstNode::List { name, codes } => {
                     if name != "identifier_list".to_string() {
-                        return Err(format!("expected type was 'identifier'."));
+                        return Err(CompileErrorKind::ExpectedIdentifier.into());
                     }
-                    let mut res: Vec<String> = vec![];
+                    let mut res: Vec<(String, Type)> = vec![];
+                    let mut ret = Type::Unknown;
                     for identifier in codes {
-                        if let AstNode::Identifier(id) = identifier {
-                            res.push(id);
+                        if let AstNode::Identifier(id) = identifier.inner().clone() {
+                            let (name, t) =
+                                split_annotation(&id).map_err(|kind| match identifier {
+                                    AstNode::Located { block, .. } => {
+                                        CompileError::from(kind).at(block)
+                                    }
+                                    _ => kind.into(),
+                                })?;
+                            if name.is_empty() {
+                                ret = t;
+                            } else {
+                                res.push((name.to_string(), t));
+                            }
                         } else {
-                            return Err(format!("expected type was 'identifier'."));
+                            return Err(CompileErrorKind::ExpectedIdentifier.into());
                         }
                     }
-                    Ok(res)
+                    Ok((res, ret))
                 }
                 _ => {
-                    return Err(format!("expected block was 'identifier_list'"));
+                    return Err(CompileErrorKind::ExpectedIdentifierList.into());
                 }
             }
         }
@@ -237,58 +484,83 @@ impl AstNode {
             AstNode::Statement { statement, options } => match statement.as_str() {
                 "set" => {
                     if options.len() != 2 {
-                        return Err(format!(
-                            "this statement takes 1 options but {} options was supplied.",
-                            options.len()
-                        ));
+                        return Err(CompileErrorKind::StatementArity {
+                            statement: "set".to_string(),
+                            expected: 2,
+                            found: options.len(),
+                        }
+                        .into());
                     }
-                    match &options[0] {
+                    match options[0].inner() {
                         AstNode::Identifier(idf) => {
-                            let var = environment.find(idf.clone())?;
+                            let var = environment.find_or_report(idf.clone());
                             let exp = &options[1].compile(environment, false)?;
                             return_type = exp.1.clone();
                             res.extend(exp.0.clone());
-                            if var.1 != "".to_string() && var.1 != exp.1 {
-                                return Err(format!(
-                                    "expected type '{}', but found type '{}'.",
-                                    var.1, exp.1
-                                ));
+                            if !check_type(&exp.1, &var.1) {
+                                environment.report(CompileErrorKind::TypeMismatch {
+                                    expected: var.1.to_string(),
+                                    found: exp.1.to_string(),
+                                });
                             }
 
-                            environment.set_type(idf.clone(), exp.1.clone())?;
+                            // 型注釈や前の代入で型が決まっていればそのまま
+                            // 未定義のときはfind_or_reportで記録してある
+                            if var.1 == Type::Unknown {
+                                let _ = environment.set_type(idf.clone(), exp.1.clone());
+                            }
                             add_u8(&mut res, Opecodes::OverWriteSP as u8);
                             add_i64(&mut res, var.0);
                         }
-                        _ => return Err(format!("expected type was 'identifier'.")),
+                        _ => return Err(CompileErrorKind::ExpectedIdentifier.into()),
                     }
                 }
                 "lambda" => {
                     if options.len() == 0 {
-                        return Err("statement 'lambda' needs argments list.".to_string());
+                        return Err(CompileErrorKind::StatementArity {
+                            statement: "lambda".to_string(),
+                            expected: 1,
+                            found: 0,
+                        }
+                        .into());
                     }
                     if options.len() > 2 {
-                        return Err(format!(
-                            "statement 'lambda' takes 1 options but {} options was supplied.",
-                            options.len()
-                        ));
+                        return Err(CompileErrorKind::StatementArity {
+                            statement: "lambda".to_string(),
+                            expected: 2,
+                            found: options.len(),
+                        }
+                        .into());
                     }
 
                     let mut compile_point = 0;
                     let mut is_stack_pushed = false;
+                    let mut params: Vec<String> = vec![];
+                    let mut ret_annotation = Type::Unknown;
                     match get_identifier_list(options[0].clone()) {
-                        Ok(argments) => {
-                            let mut hash: HashMap<String, (i64, String)> = HashMap::default();
-                            for (i, var) in argments.iter().enumerate() {
-                                hash.insert(var.to_string(), (-(i as i64 + 1) * 8, "".to_string()));
+                        Ok((argments, ret)) => {
+                            let mut hash: HashMap<String, (i64, Type)> = HashMap::default();
+                            for (i, (var, t)) in argments.iter().enumerate() {
+                                hash.insert(var.to_string(), (-(i as i64 + 1) * 8, t.clone()));
                             }
+                            params = argments.into_iter().map(|(var, _)| var).collect();
+                            ret_annotation = ret;
                             environment.stack.push(hash);
                             is_stack_pushed = true;
                             compile_point = 1;
 
                             if options.len() == 1 {
-                                return Err("statement 'lambda' needs program.".to_string());
+                                return Err(CompileErrorKind::StatementArity {
+                                    statement: "lambda".to_string(),
+                                    expected: 2,
+                                    found: 1,
+                                }
+                                .into());
                             }
                         }
+                        Err(err) if matches!(err.kind, CompileErrorKind::UnknownType(_)) => {
+                            return Err(err)
+                        }
                         Err(_) => {}
                     }
 
@@ -302,14 +574,31 @@ impl AstNode {
 
                         add_u8(&mut res, Opecodes::SetFP as u8); // FP設定
 
-                        let (bytes, ret_type) =
+                        let outer_depth = environment.depth;
+                        environment.depth = 0;
+                        let (bytes, mut ret_type) =
                             options[compile_point].compile(environment, false)?;
+                        environment.depth = outer_depth;
                         res.extend(bytes);
-                        return_type = ret_type;
+                        if ret_annotation != Type::Unknown {
+                            if !check_type(&ret_type, &ret_annotation) {
+                                environment.report(CompileErrorKind::TypeMismatch {
+                                    expected: ret_annotation.to_string(),
+                                    found: ret_type.to_string(),
+                                });
+                            }
+                            ret_type = ret_annotation;
+                        }
 
                         add_u8(&mut res, Opecodes::SetRET as u8); // リターンする値を設定
                         add_u8(&mut res, Opecodes::ExportFP as u8); // 引数変数削除のため
+
+                        // 引数の型は注釈か本体で代入された型、どちらもなければUnknown
+                        let mut param_types: Vec<Type> = vec![];
                         if is_stack_pushed {
+                            for param in params.iter() {
+                                param_types.push(environment.find_or_report(param.clone()).1);
+                            }
                             if let Some(variables) = environment.stack.last() {
                                 for _ in 0..variables.len() {
                                     add_u8(&mut res, Opecodes::PopS64 as u8);
@@ -327,14 +616,27 @@ impl AstNode {
 
                         add_u8(&mut res, Opecodes::PushS64 as u8);
                         add_i64(&mut res, (jump_pos_as_real + 9) as i64);
+                        return_type = Type::Function(param_types, Box::new(ret_type));
                     }
                 }
                 "if" => {
                     if options.len() != 3 {
-                        return Err("statement 'if' needs three options.".to_string());
+                        return Err(CompileErrorKind::StatementArity {
+                            statement: "if".to_string(),
+                            expected: 3,
+                            found: options.len(),
+                        }
+                        .into());
                     }
 
                     let exp = options[0].compile(environment, false)?;
+                    // 条件はboolean（互換性のためintegerの0判定も許可）
+                    if !check_type(&exp.1, &Type::Bool) && !check_type(&exp.1, &Type::Integer) {
+                        environment.report(CompileErrorKind::TypeMismatch {
+                            expected: Type::Bool.to_string(),
+                            found: exp.1.to_string(),
+                        });
+                    }
 
                     res.extend(exp.0);
                     add_u8(&mut res, Opecodes::PushS64 as u8);
@@ -369,174 +671,558 @@ impl AstNode {
                         }
                     }
                 }
-                _ => return Err(format!("unknown statement '{}'.", statement)),
+                "while" => unsafe {
+                    if options.len() != 2 {
+                        return Err(CompileErrorKind::StatementArity {
+                            statement: "while".to_string(),
+                            expected: 2,
+                            found: options.len(),
+                        }
+                        .into());
+                    }
+
+                    let loop_start = CURRENT_POS;
+                    let exp = options[0].compile(environment, false)?;
+                    if !check_type(&exp.1, &Type::Bool) && !check_type(&exp.1, &Type::Integer) {
+                        environment.report(CompileErrorKind::TypeMismatch {
+                            expected: Type::Bool.to_string(),
+                            found: exp.1.to_string(),
+                        });
+                    }
+
+                    res.extend(exp.0);
+                    add_u8(&mut res, Opecodes::PushS64 as u8);
+                    let jump_pos_outside = res.len();
+                    add_i64(&mut res, 0);
+                    add_u8(&mut res, Opecodes::IfNotJump as u8);
+
+                    let body = options[1].compile(environment, false)?;
+                    res.extend(body.0);
+                    add_u8(&mut res, Opecodes::PopS64 as u8); // 本体の値を捨てる
+
+                    add_u8(&mut res, Opecodes::PushS64 as u8);
+                    add_i64(&mut res, loop_start as i64);
+                    add_u8(&mut res, Opecodes::Jump as u8);
+
+                    let bytes = (CURRENT_POS as i64).to_le_bytes();
+                    res[jump_pos_outside..jump_pos_outside + 8].copy_from_slice(&bytes);
+
+                    // while自体の値は0
+                    add_u8(&mut res, Opecodes::PushS64 as u8);
+                    add_i64(&mut res, 0);
+                    return_type = Type::Integer;
+                },
+                _ => return Err(CompileErrorKind::UnknownStatement(statement.clone()).into()),
             },
             AstNode::ValueInteger(num) => {
                 add_u8(&mut res, Opecodes::PushS64 as u8);
                 add_i64(&mut res, *num);
-                return_type = "integer".to_string();
+                return_type = Type::Integer;
             }
             AstNode::ValueFloat(num) => {
                 add_u8(&mut res, Opecodes::PushS64 as u8);
                 add_f64(&mut res, *num);
-                return_type = "float".to_string();
+                return_type = Type::Float;
+            }
+            AstNode::ValueBool(b) => {
+                add_u8(&mut res, Opecodes::PushS64 as u8);
+                add_i64(&mut res, *b as i64);
+                return_type = Type::Bool;
             }
             AstNode::ValueStr(str) => {
-                // 未完成
-                let bytes = str.clone().into_bytes();
-                for b in bytes {
-                    res.push(b);
-                }
-                return_type = "string".to_string();
+                let index = match environment.constants.iter().position(|c| c == str) {
+                    Some(index) => index,
+                    None => {
+                        environment.constants.push(str.clone());
+                        environment.constants.len() - 1
+                    }
+                };
+                add_u8(&mut res, Opecodes::PushStr as u8);
+                add_u64(&mut res, index as u64);
+                return_type = Type::String;
             }
             AstNode::List { name, codes } => match name.as_str() {
                 "list" => {
-                    if codes.len() == 0 {
-                        return Ok((res, return_type));
-                    }
-
+                    // リストは最後の式の値だけをスタックに残す
                     let mut start_compile_point = 0;
-                    let mut is_stack_pushed = false;
-                    match get_identifier_list(codes[0].clone()) {
-                        Ok(local_variables) => {
-                            let mut hash: HashMap<String, (i64, String)> = HashMap::default();
-                            for (i, var) in local_variables.iter().enumerate() {
-                                hash.insert(var.to_string(), (i as i64 * 8, "".to_string()));
+                    let mut local_count = 0;
+                    let outer_depth = environment.depth;
+                    if let Some(first) = codes.first() {
+                        let local_variables = match get_identifier_list(first.clone()) {
+                            Ok((_, ret)) if ret != Type::Unknown => {
+                                // 戻り値の型注釈はlambdaの引数にだけ書ける
+                                return Err(CompileErrorKind::ExpectedIdentifier.into());
+                            }
+                            Ok((local_variables, _)) => Some(local_variables),
+                            Err(err) if matches!(err.kind, CompileErrorKind::UnknownType(_)) => {
+                                return Err(err)
+                            }
+                            Err(_) => None,
+                        };
+                        if let Some(local_variables) = local_variables {
+                            let mut hash: HashMap<String, (i64, Type)> = HashMap::default();
+                            for (i, (var, t)) in local_variables.iter().enumerate() {
+                                hash.insert(
+                                    var.to_string(),
+                                    ((outer_depth + i as i64) * 8, t.clone()),
+                                );
                                 add_u8(&mut res, Opecodes::PushS64 as u8);
                                 add_u64(&mut res, 0);
                             }
+                            local_count = local_variables.len();
+                            environment.depth += local_count as i64;
                             environment.stack.push(hash);
-                            is_stack_pushed = true;
                             start_compile_point = 1;
                         }
-                        Err(_) => {}
                     }
 
-                    for code in codes[start_compile_point..].iter() {
+                    if codes.len() <= start_compile_point {
+                        // 式がない場合は0を値とする
+                        add_u8(&mut res, Opecodes::PushS64 as u8);
+                        add_i64(&mut res, 0);
+                        return_type = Type::Integer;
+                    }
+                    for (i, code) in codes[start_compile_point..].iter().enumerate() {
+                        if i != 0 {
+                            add_u8(&mut res, Opecodes::PopS64 as u8); // 前の式の値を捨てる
+                        }
                         let (bytes, ret_type) = code.compile(environment, false)?;
                         res.extend(bytes);
                         return_type = ret_type;
                     }
 
-                    if is_stack_pushed {
-                        if let Some(variables) = environment.stack.last() {
-                            for _ in 0..variables.len() {
+                    if start_compile_point == 1 {
+                        if local_count != 0 {
+                            // 値を退避してローカル変数を削除
+                            add_u8(&mut res, Opecodes::SetRET as u8);
+                            for _ in 0..local_count {
                                 add_u8(&mut res, Opecodes::PopS64 as u8);
                             }
+                            add_u8(&mut res, Opecodes::PushRET as u8);
                         }
                         environment.stack.pop();
                     }
+                    environment.depth = outer_depth;
                 }
-                _ => return Err(format!("unknow list node '{}'.", name)),
+                _ => return Err(CompileErrorKind::UnknownList(name.clone()).into()),
             },
             AstNode::Identifier(str) => {
-                let var = environment.find(str.clone())?;
+                let var = environment.find_or_report(str.clone());
                 add_u8(&mut res, Opecodes::CopySP as u8);
                 add_i64(&mut res, var.0);
                 return_type = var.1.clone();
             }
+            AstNode::Located { block, node } => unsafe {
+                let start = CURRENT_POS;
+                let first = environment.diagnostics.len();
+                let result = node.compile(environment, false);
+                for err in environment.diagnostics[first..].iter_mut() {
+                    err.block = err.block.or(Some(*block));
+                }
+                let (bytes, ret_type) = result.map_err(|err| err.at(*block))?;
+                res.extend(bytes);
+                return_type = ret_type;
+                environment.source_map.push(SourceRange {
+                    start,
+                    end: CURRENT_POS,
+                    block: *block,
+                });
+            },
             AstNode::Function { func, args } => match func.as_str() {
                 "addi" => {
-                    let (a, b) = get_binop_args(args, "integer".to_string(), environment)?;
+                    let (a, b) = get_binop_args(args, Type::Integer, environment)?;
                     res.extend(a.0);
                     res.extend(b.0);
                     add_u8(&mut res, Opecodes::AddI as u8);
-                    return_type = "integer".to_string();
+                    return_type = Type::Integer;
                 }
                 "subi" => {
-                    let (a, b) = get_binop_args(args, "integer".to_string(), environment)?;
+                    let (a, b) = get_binop_args(args, Type::Integer, environment)?;
                     res.extend(a.0);
                     res.extend(b.0);
                     add_u8(&mut res, Opecodes::SubI as u8);
-                    return_type = "integer".to_string();
+                    return_type = Type::Integer;
                 }
                 "muli" => {
-                    let (a, b) = get_binop_args(args, "integer".to_string(), environment)?;
+                    let (a, b) = get_binop_args(args, Type::Integer, environment)?;
                     res.extend(a.0);
                     res.extend(b.0);
                     add_u8(&mut res, Opecodes::MulI as u8);
-                    return_type = "integer".to_string();
+                    return_type = Type::Integer;
                 }
                 "divi" => {
-                    let (a, b) = get_binop_args(args, "integer".to_string(), environment)?;
+                    let (a, b) = get_binop_args(args, Type::Integer, environment)?;
                     res.extend(a.0);
                     res.extend(b.0);
                     add_u8(&mut res, Opecodes::DivI as u8);
-                    return_type = "integer".to_string();
+                    return_type = Type::Integer;
                 }
                 "modi" => {
-                    let (a, b) = get_binop_args(args, "integer".to_string(), environment)?;
+                    let (a, b) = get_binop_args(args, Type::Integer, environment)?;
                     res.extend(a.0);
                     res.extend(b.0);
                     add_u8(&mut res, Opecodes::ModI as u8);
-                    return_type = "integer".to_string();
+                    return_type = Type::Integer;
                 }
                 "printi" => {
                     if args.len() != 1 {
-                        return Err(format!(
-                            "this function takes 1 arguments but {} argument was supplied.",
-                            args.len()
-                        ));
+                        return Err(CompileErrorKind::ArityMismatch {
+                            expected: 1,
+                            found: args.len(),
+                        }
+                        .into());
                     }
                     let a = args[0].compile(environment, false)?;
-                    if !check_type(a.1.clone(), "integer".to_string()) {
-                        return Err(format!("expected type integer, but found type {}.", a.1));
+                    if !check_type(&a.1, &Type::Integer) {
+                        environment.report(CompileErrorKind::TypeMismatch {
+                            expected: Type::Integer.to_string(),
+                            found: a.1.to_string(),
+                        });
                     }
 
                     res.extend(a.0);
                     add_u8(&mut res, Opecodes::OutputI as u8);
-                    return_type = "integer".to_string();
+                    return_type = Type::Integer;
                 }
                 "addf" => {
-                    let (a, b) = get_binop_args(args, "float".to_string(), environment)?;
+                    let (a, b) = get_binop_args(args, Type::Float, environment)?;
                     res.extend(a.0);
                     res.extend(b.0);
                     add_u8(&mut res, Opecodes::AddF as u8);
-                    return_type = "float".to_string();
+                    return_type = Type::Float;
                 }
                 "subf" => {
-                    let (a, b) = get_binop_args(args, "float".to_string(), environment)?;
+                    let (a, b) = get_binop_args(args, Type::Float, environment)?;
                     res.extend(a.0);
                     res.extend(b.0);
                     add_u8(&mut res, Opecodes::SubF as u8);
-                    return_type = "float".to_string();
+                    return_type = Type::Float;
                 }
                 "mulf" => {
-                    let (a, b) = get_binop_args(args, "float".to_string(), environment)?;
+                    let (a, b) = get_binop_args(args, Type::Float, environment)?;
                     res.extend(a.0);
                     res.extend(b.0);
                     add_u8(&mut res, Opecodes::MulF as u8);
-                    return_type = "float".to_string();
+                    return_type = Type::Float;
                 }
                 "divf" => {
-                    let (a, b) = get_binop_args(args, "float".to_string(), environment)?;
+                    let (a, b) = get_binop_args(args, Type::Float, environment)?;
                     res.extend(a.0);
                     res.extend(b.0);
                     add_u8(&mut res, Opecodes::DivF as u8);
-                    return_type = "float".to_string();
+                    return_type = Type::Float;
                 }
                 "modf" => {
-                    let (a, b) = get_binop_args(args, "float".to_string(), environment)?;
+                    let (a, b) = get_binop_args(args, Type::Float, environment)?;
                     res.extend(a.0);
                     res.extend(b.0);
                     add_u8(&mut res, Opecodes::ModF as u8);
-                    return_type = "float".to_string();
+                    return_type = Type::Float;
                 }
                 "printf" => {
                     if args.len() != 1 {
-                        return Err(format!(
-                            "this function takes 1 arguments but {} argument was supplied.",
-                            args.len()
-                        ));
+                        return Err(CompileErrorKind::ArityMismatch {
+                            expected: 1,
+                            found: args.len(),
+                        }
+                        .into());
                     }
                     let a = args[0].compile(environment, false)?;
-                    if !check_type(a.1.clone(), "float".to_string()) {
-                        return Err(format!("expected type float, but found type {}.", a.1));
+                    if !check_type(&a.1, &Type::Float) {
+                        environment.report(CompileErrorKind::TypeMismatch {
+                            expected: Type::Float.to_string(),
+                            found: a.1.to_string(),
+                        });
                     }
 
                     res.extend(a.0);
                     add_u8(&mut res, Opecodes::OutputF as u8);
-                    return_type = "float".to_string();
+                    return_type = Type::Float;
+                }
+                "eqi" => {
+                    let (a, b) = get_binop_args(args, Type::Integer, environment)?;
+                    res.extend(a.0);
+                    res.extend(b.0);
+                    add_u8(&mut res, Opecodes::EqI as u8);
+                    return_type = Type::Bool;
+                }
+                "nei" => {
+                    let (a, b) = get_binop_args(args, Type::Integer, environment)?;
+                    res.extend(a.0);
+                    res.extend(b.0);
+                    add_u8(&mut res, Opecodes::NeI as u8);
+                    return_type = Type::Bool;
+                }
+                "lti" => {
+                    let (a, b) = get_binop_args(args, Type::Integer, environment)?;
+                    res.extend(a.0);
+                    res.extend(b.0);
+                    add_u8(&mut res, Opecodes::LtI as u8);
+                    return_type = Type::Bool;
+                }
+                "gti" => {
+                    let (a, b) = get_binop_args(args, Type::Integer, environment)?;
+                    res.extend(a.0);
+                    res.extend(b.0);
+                    add_u8(&mut res, Opecodes::GtI as u8);
+                    return_type = Type::Bool;
+                }
+                "lei" => {
+                    let (a, b) = get_binop_args(args, Type::Integer, environment)?;
+                    res.extend(a.0);
+                    res.extend(b.0);
+                    add_u8(&mut res, Opecodes::LeI as u8);
+                    return_type = Type::Bool;
+                }
+                "gei" => {
+                    let (a, b) = get_binop_args(args, Type::Integer, environment)?;
+                    res.extend(a.0);
+                    res.extend(b.0);
+                    add_u8(&mut res, Opecodes::GeI as u8);
+                    return_type = Type::Bool;
+                }
+                "eqf" => {
+                    let (a, b) = get_binop_args(args, Type::Float, environment)?;
+                    res.extend(a.0);
+                    res.extend(b.0);
+                    add_u8(&mut res, Opecodes::EqF as u8);
+                    return_type = Type::Bool;
+                }
+                "nef" => {
+                    let (a, b) = get_binop_args(args, Type::Float, environment)?;
+                    res.extend(a.0);
+                    res.extend(b.0);
+                    add_u8(&mut res, Opecodes::NeF as u8);
+                    return_type = Type::Bool;
+                }
+                "ltf" => {
+                    let (a, b) = get_binop_args(args, Type::Float, environment)?;
+                    res.extend(a.0);
+                    res.extend(b.0);
+                    add_u8(&mut res, Opecodes::LtF as u8);
+                    return_type = Type::Bool;
+                }
+                "gtf" => {
+                    let (a, b) = get_binop_args(args, Type::Float, environment)?;
+                    res.extend(a.0);
+                    res.extend(b.0);
+                    add_u8(&mut res, Opecodes::GtF as u8);
+                    return_type = Type::Bool;
+                }
+                "lef" => {
+                    let (a, b) = get_binop_args(args, Type::Float, environment)?;
+                    res.extend(a.0);
+                    res.extend(b.0);
+                    add_u8(&mut res, Opecodes::LeF as u8);
+                    return_type = Type::Bool;
+                }
+                "gef" => {
+                    let (a, b) = get_binop_args(args, Type::Float, environment)?;
+                    res.extend(a.0);
+                    res.extend(b.0);
+                    add_u8(&mut res, Opecodes::GeF as u8);
+                    return_type = Type::Bool;
+                }
+                "and" => {
+                    let (a, b) = get_binop_args(args, Type::Bool, environment)?;
+                    res.extend(a.0);
+                    res.extend(b.0);
+                    add_u8(&mut res, Opecodes::And as u8);
+                    return_type = Type::Bool;
+                }
+                "or" => {
+                    let (a, b) = get_binop_args(args, Type::Bool, environment)?;
+                    res.extend(a.0);
+                    res.extend(b.0);
+                    add_u8(&mut res, Opecodes::Or as u8);
+                    return_type = Type::Bool;
+                }
+                "not" => {
+                    if args.len() != 1 {
+                        return Err(CompileErrorKind::ArityMismatch {
+                            expected: 1,
+                            found: args.len(),
+                        }
+                        .into());
+                    }
+                    let a = args[0].compile(environment, false)?;
+                    if !check_type(&a.1, &Type::Bool) {
+                        environment.report(CompileErrorKind::TypeMismatch {
+                            expected: Type::Bool.to_string(),
+                            found: a.1.to_string(),
+                        });
+                    }
+
+                    res.extend(a.0);
+                    add_u8(&mut res, Opecodes::Not as u8);
+                    return_type = Type::Bool;
+                }
+                "prints" => {
+                    if args.len() != 1 {
+                        return Err(CompileErrorKind::ArityMismatch {
+                            expected: 1,
+                            found: args.len(),
+                        }
+                        .into());
+                    }
+                    let a = args[0].compile(environment, false)?;
+                    if !check_type(&a.1, &Type::String) {
+                        environment.report(CompileErrorKind::TypeMismatch {
+                            expected: Type::String.to_string(),
+                            found: a.1.to_string(),
+                        });
+                    }
+
+                    res.extend(a.0);
+                    add_u8(&mut res, Opecodes::OutputS as u8);
+                    return_type = Type::String;
+                }
+                "concat" => {
+                    let (a, b) = get_binop_args(args, Type::String, environment)?;
+                    res.extend(a.0);
+                    res.extend(b.0);
+                    add_u8(&mut res, Opecodes::ConcatS as u8);
+                    return_type = Type::String;
+                }
+                "eqs" => {
+                    let (a, b) = get_binop_args(args, Type::String, environment)?;
+                    res.extend(a.0);
+                    res.extend(b.0);
+                    add_u8(&mut res, Opecodes::EqS as u8);
+                    return_type = Type::Bool;
+                }
+                "length" => {
+                    let a = compile_args(args, 1, environment)?.remove(0);
+                    if !check_type(&a.1, &Type::String) && element_type(&a.1).is_none() {
+                        environment.report(CompileErrorKind::TypeMismatch {
+                            expected: "string or array".to_string(),
+                            found: a.1.to_string(),
+                        });
+                    }
+
+                    res.extend(a.0);
+                    add_u8(&mut res, Opecodes::Length as u8);
+                    return_type = Type::Integer;
+                }
+                "substring" => {
+                    // substring 文字列 開始位置 文字数
+                    let a = compile_args(args, 3, environment)?;
+                    for (i, expected_type) in [Type::String, Type::Integer, Type::Integer]
+                        .iter()
+                        .enumerate()
+                    {
+                        if !check_type(&a[i].1, expected_type) {
+                            environment.report(CompileErrorKind::TypeMismatch {
+                                expected: expected_type.to_string(),
+                                found: a[i].1.to_string(),
+                            });
+                        }
+                    }
+                    for (bytes, _) in a {
+                        res.extend(bytes);
+                    }
+                    add_u8(&mut res, Opecodes::SubstrS as u8);
+                    return_type = Type::String;
+                }
+                "make-array" => {
+                    // make-array 要素数 初期値
+                    let a = compile_args(args, 2, environment)?;
+                    if !check_type(&a[0].1, &Type::Integer) {
+                        environment.report(CompileErrorKind::TypeMismatch {
+                            expected: Type::Integer.to_string(),
+                            found: a[0].1.to_string(),
+                        });
+                    }
+                    return_type = array_type(&a[1].1);
+                    for (bytes, _) in a {
+                        res.extend(bytes);
+                    }
+                    add_u8(&mut res, Opecodes::MakeArray as u8);
+                }
+                "get" => {
+                    // get 配列 添字
+                    let a = compile_args(args, 2, environment)?;
+                    let element = match element_type(&a[0].1) {
+                        Some(element) => element,
+                        None => {
+                            environment.report(CompileErrorKind::TypeMismatch {
+                                expected: "array".to_string(),
+                                found: a[0].1.to_string(),
+                            });
+                            Type::Unknown
+                        }
+                    };
+                    if !check_type(&a[1].1, &Type::Integer) {
+                        environment.report(CompileErrorKind::TypeMismatch {
+                            expected: Type::Integer.to_string(),
+                            found: a[1].1.to_string(),
+                        });
+                    }
+                    return_type = element;
+                    for (bytes, _) in a {
+                        res.extend(bytes);
+                    }
+                    add_u8(&mut res, Opecodes::GetA as u8);
+                }
+                "set" => {
+                    // set 配列 添字 値
+                    let a = compile_args(args, 3, environment)?;
+                    let element = match element_type(&a[0].1) {
+                        Some(element) => element,
+                        None => {
+                            environment.report(CompileErrorKind::TypeMismatch {
+                                expected: "array".to_string(),
+                                found: a[0].1.to_string(),
+                            });
+                            Type::Unknown
+                        }
+                    };
+                    if !check_type(&a[1].1, &Type::Integer) {
+                        environment.report(CompileErrorKind::TypeMismatch {
+                            expected: Type::Integer.to_string(),
+                            found: a[1].1.to_string(),
+                        });
+                    }
+                    if element != Type::Unknown && !check_type(&a[2].1, &element) {
+                        environment.report(CompileErrorKind::TypeMismatch {
+                            expected: element.to_string(),
+                            found: a[2].1.to_string(),
+                        });
+                    }
+                    return_type = a[2].1.clone();
+                    for (bytes, _) in a {
+                        res.extend(bytes);
+                    }
+                    add_u8(&mut res, Opecodes::SetA as u8);
+                }
+                "push" => {
+                    // push 配列 値 配列の末尾に追加して配列を返す
+                    let a = compile_args(args, 2, environment)?;
+                    let element = match element_type(&a[0].1) {
+                        Some(element) => element,
+                        None => {
+                            environment.report(CompileErrorKind::TypeMismatch {
+                                expected: "array".to_string(),
+                                found: a[0].1.to_string(),
+                            });
+                            Type::Unknown
+                        }
+                    };
+                    if element != Type::Unknown && !check_type(&a[1].1, &element) {
+                        environment.report(CompileErrorKind::TypeMismatch {
+                            expected: element.to_string(),
+                            found: a[1].1.to_string(),
+                        });
+                    }
+                    return_type = if element == Type::Unknown {
+                        array_type(&a[1].1)
+                    } else {
+                        a[0].1.clone()
+                    };
+                    for (bytes, _) in a {
+                        res.extend(bytes);
+                    }
+                    add_u8(&mut res, Opecodes::PushA as u8);
                 }
                 _ => unsafe {
                     add_u8(&mut res, Opecodes::PushFP as u8);
@@ -545,19 +1231,49 @@ impl AstNode {
                     let jump_pos = res.len(); // 戻る場所を指定
                     add_u64(&mut res, 0);
 
+                    let outer_depth = environment.depth;
+                    environment.depth += 2; // FPと戻る場所
+                    let mut arg_types: Vec<Type> = vec![];
                     for arg in args.iter().rev() {
                         let a = arg.compile(environment, false)?;
                         res.extend(a.0);
+                        arg_types.insert(0, a.1);
+                        environment.depth += 1;
                     }
+                    environment.depth = outer_depth;
 
-                    let func_pos = environment.find(func.clone())?;
+                    let func_pos = environment.find_or_report(func.clone());
+                    // 型の分かっている関数なら引数の数と型を調べる
+                    // 数が違うとフレームが壊れるので、ここで止める
+                    match &func_pos.1 {
+                        Type::Function(params, ret) => {
+                            if params.len() != args.len() {
+                                return Err(CompileErrorKind::ArityMismatch {
+                                    expected: params.len(),
+                                    found: args.len(),
+                                }
+                                .into());
+                            }
+                            for (param, found) in params.iter().zip(arg_types.iter()) {
+                                if !check_type(found, param) {
+                                    environment.report(CompileErrorKind::TypeMismatch {
+                                        expected: param.to_string(),
+                                        found: found.to_string(),
+                                    });
+                                }
+                            }
+                            return_type = *ret.clone();
+                        }
+                        Type::Unknown => {}
+                        found => environment.report(CompileErrorKind::TypeMismatch {
+                            expected: "function".to_string(),
+                            found: found.to_string(),
+                        }),
+                    }
                     add_u8(&mut res, Opecodes::CopySP as u8);
                     add_i64(&mut res, func_pos.0);
                     add_u8(&mut res, Opecodes::Jump as u8);
 
-                    return_type = func_pos.1.clone();
-
-                    println!("curpos:{}", CURRENT_POS);
                     let return_pos_bytes: [u8; 8] = (CURRENT_POS as i64).to_le_bytes();
                     for i in 0..8 {
                         res[jump_pos + i] = return_pos_bytes[i];
@@ -571,22 +1287,95 @@ impl AstNode {
 
         Ok((res, return_type))
     }
+
+    // プログラム全体をコンパイルして、最後にEndを付けた入れ物にする
+    // 途中で記録したエラーと、止まったときはそのエラーをまとめて返す
+    // 型は先に推論で調べ、そこでエラーがあればコード生成側の型のエラーは重複するので除く
+    pub fn compile_program(
+        &self,
+        environment: &mut Environment,
+    ) -> Result<(Program, Type), Vec<CompileError>> {
+        let inference = infer::infer(self);
+        let result = self.compile(environment, true);
+        let mut errors = std::mem::take(&mut environment.diagnostics);
+        if !inference.errors.is_empty() {
+            errors.retain(|err| {
+                !matches!(
+                    err.kind,
+                    CompileErrorKind::TypeMismatch { .. } | CompileErrorKind::UndefinedVariable(_)
+                )
+            });
+            errors.splice(0..0, inference.errors);
+        }
+        let mut code = match result {
+            Ok((code, _)) if errors.is_empty() => code,
+            Ok(_) => return Err(errors),
+            Err(err) => {
+                // 引数の数の食い違いは推論でも報告している
+                if !errors.contains(&err) {
+                    errors.push(err);
+                }
+                return Err(errors);
+            }
+        };
+        code.push(Opecodes::End as u8);
+        Ok((
+            Program {
+                entry: 0,
+                code,
+                constants: environment.constants.clone(),
+                debug: if environment.source_map.is_empty() {
+                    None
+                } else {
+                    Some(SourceMap {
+                        ranges: environment.source_map.clone(),
+                    })
+                },
+            },
+            inference.ty,
+        ))
+    }
+}
+
+// 命令の直後にある8バイトのオペランドを読む
+fn read_operand(code: &[u8], i: u32) -> Result<[u8; 8], RuntimeError> {
+    code.get(i as usize + 1..i as usize + 9)
+        .and_then(|slice| slice.try_into().ok())
+        .ok_or(RuntimeError::TruncatedOperand(i))
+}
+
+// FPにオフセットを足した位置　溢れるならフレームの外
+fn frame_point(fp: i64, operand: [u8; 8]) -> Result<i64, RuntimeError> {
+    let offset = i64::from_le_bytes(operand);
+    fp.checked_add(offset)
+        .ok_or(RuntimeError::FrameOutOfRange(offset))
+}
+
+const STACK_SIZE: usize = 100000;
+const MAX_ARRAY_LENGTH: usize = 1 << 24;
+
+const DEFAULT_MAX_STEPS: u64 = 100_000_000;
+
+// 実行する命令数の上限と、外から実行を止めるためのフラグ
+#[derive(Resource, Clone)]
+pub struct VmOptions {
+    pub max_steps: Option<u64>,
+    pub cancel: Arc<AtomicBool>,
 }
 
-fn bytes_to_i64(bytes: &[u8], start: usize) -> Result<i64, String> {
-    // 指定位置から8バイト取り出せるかチェック
-    if start + 8 <= bytes.len() {
-        let slice = &bytes[start..start + 8]; // スライスを取得
-        let array: [u8; 8] = slice.try_into().unwrap(); // [u8; 8] に変換
-        Ok(i64::from_le_bytes(array)) // リトルエンディアンで変換
-    } else {
-        Err("could not find number.".to_string())
+impl Default for VmOptions {
+    fn default() -> Self {
+        VmOptions {
+            max_steps: Some(DEFAULT_MAX_STEPS),
+            cancel: Arc::new(AtomicBool::new(false)),
+        }
     }
 }
 
 struct Stack {
     pub sp: usize,
-    pub stack: [u8; 100000],
+    pub stack: [u8; STACK_SIZE],
+    pub tags: [bool; STACK_SIZE / 8], // 8バイトごとにヒープへの参照かどうか
 }
 impl Stack {
     pub fn print(&self) {
@@ -595,219 +1384,628 @@ impl Stack {
         }
         println!("");
     }
-    pub fn push64(&mut self, code: [u8; 8]) {
+    fn read(&self, point: usize) -> [u8; 8] {
+        let mut res: [u8; 8] = [0; 8];
+        for i in 0..8 {
+            res[i] = self.stack[point + i];
+        }
+        res
+    }
+    // フレームの中の値はスタックに積まれている範囲にしかアクセスできない
+    fn check_point(&self, point: i64) -> Result<usize, RuntimeError> {
+        if point < 0 || point % 8 != 0 || point as usize + 8 > self.sp {
+            return Err(RuntimeError::FrameOutOfRange(point));
+        }
+        Ok(point as usize)
+    }
+    pub fn push64(&mut self, code: [u8; 8]) -> Result<(), RuntimeError> {
+        if self.sp + 8 > STACK_SIZE {
+            return Err(RuntimeError::StackOverflow);
+        }
         for i in 0..8 {
             self.stack[self.sp + i] = code[i];
         }
+        self.tags[self.sp / 8] = false;
         self.sp += 8;
+        Ok(())
     }
-    pub fn pop64(&mut self) -> [u8; 8] {
-        let mut res: [u8; 8] = [0; 8];
-        for i in 0..8 {
-            res[i] = self.stack[self.sp - 8 + i];
+    pub fn pop64(&mut self) -> Result<[u8; 8], RuntimeError> {
+        if self.sp < 8 {
+            return Err(RuntimeError::StackUnderflow);
         }
         self.sp -= 8;
-        res
+        Ok(self.read(self.sp))
     }
-    pub fn get64(&self, point: i64) -> [u8; 8] {
-        let mut res: [u8; 8] = [0; 8];
-        for i in 0..8 {
-            res[i] = self.stack[point as usize + i];
-        }
-        res
+    pub fn get64(&self, point: i64) -> Result<[u8; 8], RuntimeError> {
+        Ok(self.read(self.check_point(point)?))
     }
-    pub fn set64(&mut self, value: [u8; 8], point: i64) {
+    pub fn set64(&mut self, value: [u8; 8], point: i64) -> Result<(), RuntimeError> {
+        let point = self.check_point(point)?;
         for i in 0..8 {
-            self.stack[point as usize + i] = value[i];
+            self.stack[point + i] = value[i];
         }
+        Ok(())
+    }
+    pub fn push_tagged(&mut self, code: [u8; 8], is_ref: bool) -> Result<(), RuntimeError> {
+        self.push64(code)?;
+        self.tags[self.sp / 8 - 1] = is_ref;
+        Ok(())
+    }
+    pub fn push_ref(&mut self, code: [u8; 8]) -> Result<(), RuntimeError> {
+        self.push_tagged(code, true)
+    }
+    pub fn pop_tagged(&mut self) -> Result<([u8; 8], bool), RuntimeError> {
+        let value = self.pop64()?;
+        Ok((value, self.tags[self.sp / 8]))
+    }
+    pub fn get_tagged(&self, point: i64) -> Result<([u8; 8], bool), RuntimeError> {
+        Ok((self.get64(point)?, self.tags[point as usize / 8]))
+    }
+    pub fn set_tagged(
+        &mut self,
+        value: [u8; 8],
+        is_ref: bool,
+        point: i64,
+    ) -> Result<(), RuntimeError> {
+        self.set64(value, point)?;
+        self.tags[point as usize / 8] = is_ref;
+        Ok(())
+    }
+    // GCのルートになるスタック上の参照
+    pub fn refs(&self) -> impl Iterator<Item = [u8; 8]> + '_ {
+        (0..self.sp / 8)
+            .filter(|slot| self.tags[*slot])
+            .map(|slot| self.read(slot * 8))
     }
 }
 
-pub fn execute_vm(code: Vec<u8>) -> Result<String, String> {
-    let mut i: u32 = 0;
+// Program::to_bytesで作った入れ物を検証してから実行する
+// 出力命令が実行されるたびに一行ずつoutputへ渡す
+pub fn execute_vm(
+    image: &[u8],
+    options: &VmOptions,
+    output: &mut dyn FnMut(String),
+) -> Result<(), VmError> {
+    let program = Program::from_bytes(image).map_err(|err| VmError {
+        error: RuntimeError::InvalidProgram(err),
+        offset: None,
+        block: None,
+    })?;
+    let mut i: u32 = program.entry;
+    run(&program.code, &program.constants, options, output, &mut i).map_err(|error| VmError {
+        error,
+        offset: Some(i),
+        block: program.debug.as_ref().and_then(|map| map.block_at(i)),
+    })
+}
+
+// iは実行中の命令の位置　エラーで止まったときはその命令を指している
+fn run(
+    code: &[u8],
+    constants: &[String],
+    options: &VmOptions,
+    output: &mut dyn FnMut(String),
+    i: &mut u32,
+) -> Result<(), RuntimeError> {
+    let mut steps: u64 = 0;
     let mut stack: Stack = Stack {
         sp: 0,
-        stack: [0; 100000],
+        stack: [0; STACK_SIZE],
+        tags: [false; STACK_SIZE / 8],
     };
+    let mut heap: Heap = Heap::default();
     let mut fp: i64 = 0;
     let mut ret: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 0];
-    let mut res = "".to_string();
+    let mut ret_is_ref = false;
     loop {
-        //print!("{}=>", i);
-        if let Some(&byte) = code.get(i as usize) {
+        if options
+            .max_steps
+            .is_some_and(|max_steps| steps >= max_steps)
+        {
+            return Err(RuntimeError::ExecutionLimitExceeded(steps));
+        }
+        if options.cancel.load(Ordering::Relaxed) {
+            return Err(RuntimeError::Cancelled);
+        }
+        steps += 1;
+
+        if heap.needs_collect() {
+            let ret_root = if ret_is_ref { Some(ret) } else { None };
+            heap.collect(stack.refs().chain(ret_root));
+        }
+        //print!("{}=>", *i);
+        if let Some(&byte) = code.get(*i as usize) {
             if let Some(opcode) = (byte as u8).try_into().ok() {
                 match opcode {
+                    Opecodes::PushS32 | Opecodes::PopS32 | Opecodes::SaveR => {
+                        return Err(RuntimeError::InvalidOpcode(byte)); // 未実装
+                    }
                     Opecodes::CopySP => {
-                        let slice = &code[(i as usize + 1)..(i as usize + 9)]; // スライスを取得
-                        let array: [u8; 8] = slice.try_into().unwrap(); // [u8; 8] に変換
-                        stack.push64(stack.get64(fp + i64::from_le_bytes(array)));
-                        i += 9;
+                        let array = read_operand(code, *i)?;
+                        let (value, is_ref) = stack.get_tagged(frame_point(fp, array)?)?;
+                        stack.push_tagged(value, is_ref)?;
+                        *i += 9;
                     }
                     Opecodes::OverWriteSP => {
-                        let value = stack.pop64();
-                        let slice = &code[(i as usize + 1)..(i as usize + 9)]; // スライスを取得
-                        let array: [u8; 8] = slice.try_into().unwrap(); // [u8; 8] に変換
-                        stack.set64(value, fp + i64::from_le_bytes(array));
-                        stack.push64(value);
-                        i += 9;
-                    }
-                    Opecodes::PushS32 => {}
+                        let (value, is_ref) = stack.pop_tagged()?;
+                        let array = read_operand(code, *i)?;
+                        stack.set_tagged(value, is_ref, frame_point(fp, array)?)?;
+                        stack.push_tagged(value, is_ref)?;
+                        *i += 9;
+                    }
                     Opecodes::PushS64 => {
-                        let slice = &code[(i as usize + 1)..(i as usize + 9)]; // スライスを取得
-                        let array: [u8; 8] = slice.try_into().unwrap(); // [u8; 8] に変換
-                        stack.push64(array);
+                        let array = read_operand(code, *i)?;
+                        stack.push64(array)?;
 
-                        i += 9;
+                        *i += 9;
                     }
-                    Opecodes::PopS32 => {}
                     Opecodes::PopS64 => {
-                        stack.pop64();
-                        i += 1;
+                        stack.pop64()?;
+                        *i += 1;
                     }
-                    Opecodes::SaveR => {}
                     Opecodes::AddI => {
-                        let value1 = stack.pop64();
-                        let value2 = stack.pop64();
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
                         stack.push64(
-                            (i64::from_le_bytes(value2) + i64::from_le_bytes(value1)).to_le_bytes(),
-                        );
-                        i += 1;
+                            i64::from_le_bytes(value2)
+                                .wrapping_add(i64::from_le_bytes(value1))
+                                .to_le_bytes(),
+                        )?;
+                        *i += 1;
                     }
                     Opecodes::SubI => {
-                        let value1 = stack.pop64();
-                        let value2 = stack.pop64();
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
                         stack.push64(
-                            (i64::from_le_bytes(value2) - i64::from_le_bytes(value1)).to_le_bytes(),
-                        );
-                        i += 1;
+                            i64::from_le_bytes(value2)
+                                .wrapping_sub(i64::from_le_bytes(value1))
+                                .to_le_bytes(),
+                        )?;
+                        *i += 1;
                     }
                     Opecodes::MulI => {
-                        let value1 = stack.pop64();
-                        let value2 = stack.pop64();
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
                         stack.push64(
-                            (i64::from_le_bytes(value2) * i64::from_le_bytes(value1)).to_le_bytes(),
-                        );
-                        i += 1;
+                            i64::from_le_bytes(value2)
+                                .wrapping_mul(i64::from_le_bytes(value1))
+                                .to_le_bytes(),
+                        )?;
+                        *i += 1;
                     }
                     Opecodes::DivI => {
-                        let value1 = stack.pop64();
-                        let value2 = stack.pop64();
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
+                        if i64::from_le_bytes(value1) == 0 {
+                            return Err(RuntimeError::DivisionByZero);
+                        }
                         stack.push64(
-                            (i64::from_le_bytes(value2) / i64::from_le_bytes(value1)).to_le_bytes(),
-                        );
-                        i += 1;
+                            i64::from_le_bytes(value2)
+                                .wrapping_div(i64::from_le_bytes(value1))
+                                .to_le_bytes(),
+                        )?;
+                        *i += 1;
                     }
                     Opecodes::ModI => {
-                        let value1 = stack.pop64();
-                        let value2 = stack.pop64();
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
+                        if i64::from_le_bytes(value1) == 0 {
+                            return Err(RuntimeError::DivisionByZero);
+                        }
                         stack.push64(
-                            (i64::from_le_bytes(value2) % i64::from_le_bytes(value1)).to_le_bytes(),
-                        );
-                        i += 1;
+                            i64::from_le_bytes(value2)
+                                .wrapping_rem(i64::from_le_bytes(value1))
+                                .to_le_bytes(),
+                        )?;
+                        *i += 1;
                     }
                     Opecodes::OutputI => {
-                        let value = stack.pop64();
-                        println!("{}", i64::from_le_bytes(value));
-                        res += &format!("{}\n", i64::from_le_bytes(value));
-                        stack.push64(value);
-                        i += 1;
+                        let value = stack.pop64()?;
+                        output(format!("{}", i64::from_le_bytes(value)));
+                        stack.push64(value)?;
+                        *i += 1;
                     }
                     Opecodes::Jump => {
-                        let pos = stack.pop64();
+                        let pos = stack.pop64()?;
                         let pos4 = [pos[0], pos[1], pos[2], pos[3]];
-                        i = u32::from_le_bytes(pos4);
+                        *i = u32::from_le_bytes(pos4);
                     }
                     Opecodes::SetFP => {
                         fp = stack.sp as i64;
-                        i += 1;
+                        *i += 1;
                     }
                     Opecodes::SetRET => {
-                        let r = stack.pop64();
+                        let (r, is_ref) = stack.pop_tagged()?;
                         for i in 0..8 {
                             ret[i] = r[i];
                         }
-                        i += 1;
+                        ret_is_ref = is_ref;
+                        *i += 1;
                     }
                     Opecodes::ResetFP => {
-                        fp = i64::from_le_bytes(stack.pop64());
-                        i += 1;
+                        fp = i64::from_le_bytes(stack.pop64()?);
+                        *i += 1;
                     }
                     Opecodes::PushRET => {
-                        stack.push64(ret);
-                        i += 1;
+                        stack.push_tagged(ret, ret_is_ref)?;
+                        *i += 1;
                     }
                     Opecodes::IfNotJump => {
-                        let pos = stack.pop64();
-                        let r = stack.pop64();
+                        let pos = stack.pop64()?;
+                        let r = stack.pop64()?;
                         let pos4 = [pos[0], pos[1], pos[2], pos[3]];
                         if i64::from_le_bytes(r) == 0 {
-                            i = u32::from_le_bytes(pos4);
+                            *i = u32::from_le_bytes(pos4);
                         } else {
-                            i += 1;
+                            *i += 1;
                         }
                     }
                     Opecodes::ExportFP => {
+                        if fp < 0 || fp as usize > stack.sp {
+                            return Err(RuntimeError::FrameOutOfRange(fp));
+                        }
                         stack.sp = fp as usize;
-                        i += 1;
+                        *i += 1;
                     }
                     Opecodes::PushFP => {
-                        stack.push64(fp.to_le_bytes());
-                        i += 1;
+                        stack.push64(fp.to_le_bytes())?;
+                        *i += 1;
                     }
                     Opecodes::AddF => {
-                        let value1 = stack.pop64();
-                        let value2 = stack.pop64();
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
                         stack.push64(
                             (f64::from_le_bytes(value2) + f64::from_le_bytes(value1)).to_le_bytes(),
-                        );
-                        i += 1;
+                        )?;
+                        *i += 1;
                     }
                     Opecodes::SubF => {
-                        let value1 = stack.pop64();
-                        let value2 = stack.pop64();
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
                         stack.push64(
                             (f64::from_le_bytes(value2) - f64::from_le_bytes(value1)).to_le_bytes(),
-                        );
-                        i += 1;
+                        )?;
+                        *i += 1;
                     }
                     Opecodes::MulF => {
-                        let value1 = stack.pop64();
-                        let value2 = stack.pop64();
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
                         stack.push64(
                             (f64::from_le_bytes(value2) * f64::from_le_bytes(value1)).to_le_bytes(),
-                        );
-                        i += 1;
+                        )?;
+                        *i += 1;
                     }
                     Opecodes::DivF => {
-                        let value1 = stack.pop64();
-                        let value2 = stack.pop64();
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
                         stack.push64(
                             (f64::from_le_bytes(value2) / f64::from_le_bytes(value1)).to_le_bytes(),
-                        );
-                        i += 1;
+                        )?;
+                        *i += 1;
                     }
                     Opecodes::ModF => {
-                        let value1 = stack.pop64();
-                        let value2 = stack.pop64();
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
                         stack.push64(
                             (f64::from_le_bytes(value2) % f64::from_le_bytes(value1)).to_le_bytes(),
-                        );
-                        i += 1;
+                        )?;
+                        *i += 1;
                     }
                     Opecodes::OutputF => {
-                        let value = stack.pop64();
-                        println!("{}", i64::from_le_bytes(value));
-                        res += &format!("{}\n", f64::from_le_bytes(value));
-                        stack.push64(value);
-                        i += 1;
+                        let value = stack.pop64()?;
+                        output(format!("{}", f64::from_le_bytes(value)));
+                        stack.push64(value)?;
+                        *i += 1;
                     }
                     Opecodes::End => {
-                        return Ok(res);
+                        return Ok(());
+                    }
+                    Opecodes::EqI => {
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
+                        stack.push64(
+                            ((i64::from_le_bytes(value2) == i64::from_le_bytes(value1)) as i64)
+                                .to_le_bytes(),
+                        )?;
+                        *i += 1;
+                    }
+                    Opecodes::NeI => {
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
+                        stack.push64(
+                            ((i64::from_le_bytes(value2) != i64::from_le_bytes(value1)) as i64)
+                                .to_le_bytes(),
+                        )?;
+                        *i += 1;
+                    }
+                    Opecodes::LtI => {
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
+                        stack.push64(
+                            ((i64::from_le_bytes(value2) < i64::from_le_bytes(value1)) as i64)
+                                .to_le_bytes(),
+                        )?;
+                        *i += 1;
+                    }
+                    Opecodes::GtI => {
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
+                        stack.push64(
+                            ((i64::from_le_bytes(value2) > i64::from_le_bytes(value1)) as i64)
+                                .to_le_bytes(),
+                        )?;
+                        *i += 1;
+                    }
+                    Opecodes::LeI => {
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
+                        stack.push64(
+                            ((i64::from_le_bytes(value2) <= i64::from_le_bytes(value1)) as i64)
+                                .to_le_bytes(),
+                        )?;
+                        *i += 1;
+                    }
+                    Opecodes::GeI => {
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
+                        stack.push64(
+                            ((i64::from_le_bytes(value2) >= i64::from_le_bytes(value1)) as i64)
+                                .to_le_bytes(),
+                        )?;
+                        *i += 1;
+                    }
+                    Opecodes::EqF => {
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
+                        stack.push64(
+                            ((f64::from_le_bytes(value2) == f64::from_le_bytes(value1)) as i64)
+                                .to_le_bytes(),
+                        )?;
+                        *i += 1;
+                    }
+                    Opecodes::NeF => {
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
+                        stack.push64(
+                            ((f64::from_le_bytes(value2) != f64::from_le_bytes(value1)) as i64)
+                                .to_le_bytes(),
+                        )?;
+                        *i += 1;
+                    }
+                    Opecodes::LtF => {
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
+                        stack.push64(
+                            ((f64::from_le_bytes(value2) < f64::from_le_bytes(value1)) as i64)
+                                .to_le_bytes(),
+                        )?;
+                        *i += 1;
+                    }
+                    Opecodes::GtF => {
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
+                        stack.push64(
+                            ((f64::from_le_bytes(value2) > f64::from_le_bytes(value1)) as i64)
+                                .to_le_bytes(),
+                        )?;
+                        *i += 1;
+                    }
+                    Opecodes::LeF => {
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
+                        stack.push64(
+                            ((f64::from_le_bytes(value2) <= f64::from_le_bytes(value1)) as i64)
+                                .to_le_bytes(),
+                        )?;
+                        *i += 1;
+                    }
+                    Opecodes::GeF => {
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
+                        stack.push64(
+                            ((f64::from_le_bytes(value2) >= f64::from_le_bytes(value1)) as i64)
+                                .to_le_bytes(),
+                        )?;
+                        *i += 1;
+                    }
+                    Opecodes::And => {
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
+                        stack.push64(
+                            ((i64::from_le_bytes(value2) != 0 && i64::from_le_bytes(value1) != 0)
+                                as i64)
+                                .to_le_bytes(),
+                        )?;
+                        *i += 1;
+                    }
+                    Opecodes::Or => {
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
+                        stack.push64(
+                            ((i64::from_le_bytes(value2) != 0 || i64::from_le_bytes(value1) != 0)
+                                as i64)
+                                .to_le_bytes(),
+                        )?;
+                        *i += 1;
+                    }
+                    Opecodes::Not => {
+                        let value = stack.pop64()?;
+                        stack.push64(((i64::from_le_bytes(value) == 0) as i64).to_le_bytes())?;
+                        *i += 1;
+                    }
+                    Opecodes::PushStr => {
+                        let index = u64::from_le_bytes(read_operand(code, *i)?);
+                        match constants.get(index as usize) {
+                            Some(str) => {
+                                stack.push_ref(heap.alloc(HeapObject::Str(str.clone())))?
+                            }
+                            None => return Err(RuntimeError::InvalidConstant(index)),
+                        }
+                        *i += 9;
+                    }
+                    Opecodes::OutputS => {
+                        let value = stack.pop64()?;
+                        let str = heap.get_str(value)?;
+                        output(str.to_string());
+                        stack.push_ref(value)?;
+                        *i += 1;
+                    }
+                    Opecodes::ConcatS => {
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
+                        let str = heap.get_str(value2)?.clone() + heap.get_str(value1)?;
+                        stack.push_ref(heap.alloc(HeapObject::Str(str)))?;
+                        *i += 1;
+                    }
+                    Opecodes::Length => {
+                        let value = stack.pop64()?;
+                        let length = heap.length(value)? as i64;
+                        stack.push64(length.to_le_bytes())?;
+                        *i += 1;
+                    }
+                    Opecodes::SubstrS => {
+                        let length = i64::from_le_bytes(stack.pop64()?);
+                        let start = i64::from_le_bytes(stack.pop64()?);
+                        let str = heap.get_str(stack.pop64()?)?;
+                        let count = str.chars().count() as i64;
+                        if start < 0 || length < 0 || start.saturating_add(length) > count {
+                            return Err(RuntimeError::SubstringOutOfRange {
+                                start,
+                                length,
+                                count,
+                            });
+                        }
+                        let sub: String = str
+                            .chars()
+                            .skip(start as usize)
+                            .take(length as usize)
+                            .collect();
+                        stack.push_ref(heap.alloc(HeapObject::Str(sub)))?;
+                        *i += 1;
+                    }
+                    Opecodes::EqS => {
+                        let value1 = stack.pop64()?;
+                        let value2 = stack.pop64()?;
+                        let eq = heap.get_str(value2)? == heap.get_str(value1)?;
+                        stack.push64((eq as i64).to_le_bytes())?;
+                        *i += 1;
+                    }
+                    Opecodes::MakeArray => {
+                        let value = stack.pop_tagged()?;
+                        let length = i64::from_le_bytes(stack.pop64()?);
+                        if length < 0 || length as usize > MAX_ARRAY_LENGTH {
+                            return Err(RuntimeError::InvalidArrayLength(length));
+                        }
+                        stack.push_ref(
+                            heap.alloc(HeapObject::Array(vec![value; length as usize])),
+                        )?;
+                        *i += 1;
+                    }
+                    Opecodes::GetA => {
+                        let index = i64::from_le_bytes(stack.pop64()?);
+                        let array = heap.get_array(stack.pop64()?)?;
+                        match array.get(index as usize) {
+                            Some((value, is_ref)) if index >= 0 => {
+                                stack.push_tagged(*value, *is_ref)?
+                            }
+                            _ => {
+                                return Err(RuntimeError::IndexOutOfBounds {
+                                    index,
+                                    length: array.len(),
+                                })
+                            }
+                        }
+                        *i += 1;
+                    }
+                    Opecodes::SetA => {
+                        let (value, is_ref) = stack.pop_tagged()?;
+                        let index = i64::from_le_bytes(stack.pop64()?);
+                        let array = heap.get_array_mut(stack.pop64()?)?;
+                        let length = array.len();
+                        match array.get_mut(index as usize) {
+                            Some(element) if index >= 0 => *element = (value, is_ref),
+                            _ => return Err(RuntimeError::IndexOutOfBounds { index, length }),
+                        }
+                        stack.push_tagged(value, is_ref)?;
+                        *i += 1;
+                    }
+                    Opecodes::PushA => {
+                        let value = stack.pop_tagged()?;
+                        let handle = stack.pop64()?;
+                        heap.push(handle, value)?;
+                        stack.push_ref(handle)?;
+                        *i += 1;
                     }
                 }
             } else {
-                return Err(format!("invalid opcode {:#X}", byte));
+                return Err(RuntimeError::InvalidOpcode(byte));
             }
+        } else {
+            return Err(RuntimeError::ProgramCounterOutOfRange(*i));
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::sexpr;
+
+    fn run_source(source: &str) -> Vec<String> {
+        let node = sexpr::read(source).unwrap();
+        let (program, _) = node.compile_program(&mut Environment::default()).unwrap();
+        let mut lines: Vec<String> = vec![];
+        execute_vm(&program.to_bytes(), &VmOptions::default(), &mut |line| {
+            lines.push(line)
+        })
+        .unwrap();
+        lines
+    }
+
+    #[test]
+    fn addresses_locals_of_a_nested_list() {
+        let source = "(list (identifier_list a)
+                        (set a 5)
+                        (printi (addi a (list (identifier_list b) (set b 7) (muli a b)))))";
+        assert_eq!(run_source(source), vec!["40"]);
+    }
+
+    #[test]
+    fn addresses_locals_of_nested_lists_in_calls() {
+        let source = "(list (identifier_list f x)
+                        (set x 2)
+                        (set f (lambda (identifier_list n)
+                                 (addi n (list (identifier_list m) (set m 10) (addi m n)))))
+                        (printi (f (addi x (list (identifier_list k) (set k 3) k))))
+                        (printi x))";
+        assert_eq!(run_source(source), vec!["20", "2"]);
+    }
+
+    #[test]
+    fn rejects_frame_offsets_that_overflow() {
+        for instruction in ["copysp", "overwritesp"] {
+            let text = format!("push.s64 1\nsetfp\n{} {}\nend", instruction, i64::MAX);
+            let (code, constants) = asm::assemble(&text).unwrap();
+            let program = Program {
+                entry: 0,
+                code,
+                constants,
+                debug: None,
+            };
+            let err = execute_vm(&program.to_bytes(), &VmOptions::default(), &mut |_| {})
+                .err()
+                .unwrap();
+            assert_eq!(err.error, RuntimeError::FrameOutOfRange(i64::MAX));
+        }
+    }
+
+    #[test]
+    fn runs_a_while_loop() {
+        let source = "(list (identifier_list i s)
+                        (set i 0)
+                        (set s 0)
+                        (while (lti i 4) (list (set s (addi s i)) (set i (addi i 1))))
+                        (printi s))";
+        assert_eq!(run_source(source), vec!["6"]);
+    }
+}
@@ -0,0 +1,172 @@
+// 命令の名前を並べたテキストからバイトコードを作る
+//     push.s64 5        ; ';'から行末まではコメント
+// loop:                 ; ラベルはその位置のオフセットになる
+//     push.s64 loop
+//     jump
+//     push.str "hello"  ; 文字列は定数テーブルに入れてその添字を積む
+//     .byte 0x1B        ; 生のバイト
+//     .const "hello"    ; 定数テーブルの末尾に文字列を足す　添字を決めておきたいときに使う
+
+use super::Opecodes;
+use std::collections::HashMap;
+
+enum Operand {
+    Value(i64),
+    Label(String),
+}
+
+enum Item {
+    Instruction(Opecodes, Option<Operand>),
+    Byte(u8),
+}
+
+// "で囲まれていれば中身の文字列
+fn parse_str(text: &str) -> Result<Option<String>, String> {
+    if text.len() < 2 || !text.starts_with('"') || !text.ends_with('"') {
+        return Ok(None);
+    }
+    let mut str = "".to_string();
+    let mut chars = text[1..text.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => str.push('\n'),
+                Some('t') => str.push('\t'),
+                Some(c @ ('"' | '\\')) => str.push(c),
+                _ => return Err(format!("invalid escape in {}.", text)),
+            },
+            _ => str.push(c),
+        }
+    }
+    Ok(Some(str))
+}
+
+fn parse_operand(text: &str, constants: &mut Vec<String>) -> Result<Operand, String> {
+    if let Some(str) = parse_str(text)? {
+        let index = match constants.iter().position(|c| *c == str) {
+            Some(index) => index,
+            None => {
+                constants.push(str);
+                constants.len() - 1
+            }
+        };
+        return Ok(Operand::Value(index as i64));
+    }
+    match text.parse::<i64>() {
+        Ok(num) => Ok(Operand::Value(num)),
+        Err(_) => match text.parse::<f64>() {
+            Ok(num) => Ok(Operand::Value(i64::from_le_bytes(num.to_le_bytes()))),
+            Err(_) if text.chars().all(|c| c.is_alphanumeric() || c == '_') => {
+                Ok(Operand::Label(text.to_string()))
+            }
+            Err(_) => Err(format!("invalid operand '{}'.", text)),
+        },
+    }
+}
+
+fn parse_byte(text: &str) -> Result<u8, String> {
+    match text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => text.parse::<u8>(),
+    }
+    .map_err(|_| format!("invalid byte '{}'.", text))
+}
+
+// ';'より後ろを消す　ただし文字列の中の';'は残す
+fn strip_comment(line: &str) -> &str {
+    let mut in_str = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_str => escaped = true,
+            '"' => in_str = !in_str,
+            ';' if !in_str => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+pub fn assemble(text: &str) -> Result<(Vec<u8>, Vec<String>), String> {
+    let mut items: Vec<(usize, Item)> = vec![];
+    let mut labels: HashMap<String, i64> = HashMap::new();
+    let mut constants: Vec<String> = vec![];
+    let mut offset: i64 = 0;
+
+    // 1回目でラベルの位置を決め、2回目でバイト列にする
+    for (number, line) in text.lines().enumerate() {
+        let error = |msg: String| format!("line {}: {}", number + 1, msg);
+        let mut line = strip_comment(line).trim();
+        if let Some((label, rest)) = line.split_once(':') {
+            if !label.is_empty() && label.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                if labels.insert(label.to_string(), offset).is_some() {
+                    return Err(error(format!("label '{}' is defined twice.", label)));
+                }
+                line = rest.trim();
+            }
+        }
+        if line.is_empty() {
+            continue;
+        }
+        let (name, operand) = match line.split_once(char::is_whitespace) {
+            Some((name, operand)) => (name, Some(operand.trim())),
+            None => (line, None),
+        };
+        if name == ".byte" {
+            let Some(operand) = operand else {
+                return Err(error("'.byte' needs a value.".to_string()));
+            };
+            items.push((number, Item::Byte(parse_byte(operand).map_err(error)?)));
+            offset += 1;
+            continue;
+        }
+        if name == ".const" {
+            match operand.map(parse_str) {
+                Some(Ok(Some(str))) => constants.push(str),
+                Some(Err(msg)) => return Err(error(msg)),
+                _ => return Err(error("'.const' needs a string.".to_string())),
+            }
+            continue;
+        }
+        let Some(opcode) = Opecodes::from_mnemonic(name) else {
+            return Err(error(format!("unknown instruction '{}'.", name)));
+        };
+        let operand = match (opcode.operand_size(), operand) {
+            (0, None) => None,
+            (0, Some(_)) => {
+                return Err(error(format!("'{}' takes no operand.", name)));
+            }
+            (_, Some(operand)) => Some(parse_operand(operand, &mut constants).map_err(error)?),
+            (_, None) => return Err(error(format!("'{}' needs an operand.", name))),
+        };
+        items.push((number, Item::Instruction(opcode, operand)));
+        offset += 1 + opcode.operand_size() as i64;
+    }
+
+    let mut code: Vec<u8> = vec![];
+    for (number, item) in items {
+        match item {
+            Item::Byte(byte) => code.push(byte),
+            Item::Instruction(opcode, operand) => {
+                code.push(opcode as u8);
+                let value = match operand {
+                    None => continue,
+                    Some(Operand::Value(value)) => value,
+                    Some(Operand::Label(label)) => match labels.get(&label) {
+                        Some(value) => *value,
+                        None => {
+                            return Err(format!(
+                                "line {}: undefined label '{}'.",
+                                number + 1,
+                                label
+                            ))
+                        }
+                    },
+                };
+                code.extend(value.to_le_bytes());
+            }
+        }
+    }
+    Ok((code, constants))
+}
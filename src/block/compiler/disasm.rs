@@ -0,0 +1,208 @@
+// バイトコードを一行一命令の読める形にする
+// ジャンプ先は直前のPushS64で積まれた値から求めてラベルを付ける
+// 定数テーブルは先頭に.constで並べ、push.strは文字列そのもので書く
+// 出力はそのままasm::assembleで同じバイト列と定数テーブルに戻せる
+
+use super::Opecodes;
+use std::collections::BTreeSet;
+
+struct Instruction {
+    offset: usize,
+    opcode: Result<Opecodes, u8>, // 無効なバイトはそのまま
+    operand: Option<[u8; 8]>,
+}
+
+fn decode(code: &[u8]) -> Vec<Instruction> {
+    let mut instructions: Vec<Instruction> = vec![];
+    let mut offset = 0;
+    while offset < code.len() {
+        let opcode = Opecodes::try_from(code[offset]).map_err(|_| code[offset]);
+        let size = opcode.map(|opcode| opcode.operand_size()).unwrap_or(0);
+        let operand = match size {
+            0 => None,
+            _ => match code.get(offset + 1..offset + 1 + size) {
+                Some(bytes) => bytes.try_into().ok(),
+                None => {
+                    // オペランドが途中で切れているので残りは生のバイトとして出す
+                    instructions.extend(code[offset..].iter().enumerate().map(|(i, byte)| {
+                        Instruction {
+                            offset: offset + i,
+                            opcode: Err(*byte),
+                            operand: None,
+                        }
+                    }));
+                    break;
+                }
+            },
+        };
+        instructions.push(Instruction {
+            offset,
+            opcode,
+            operand,
+        });
+        offset += 1 + size;
+    }
+    instructions
+}
+
+// k番目の命令がジャンプで、直前で飛び先が定数として積まれていればその位置
+fn jump_target(instructions: &[Instruction], k: usize) -> Option<i64> {
+    match instructions[k].opcode {
+        Ok(Opecodes::Jump | Opecodes::IfNotJump) if k > 0 => match &instructions[k - 1] {
+            Instruction {
+                opcode: Ok(Opecodes::PushS64),
+                operand: Some(operand),
+                ..
+            } => Some(i64::from_le_bytes(*operand)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// asmで読める形で文字列を書く
+fn quote(str: &str) -> String {
+    let mut res = "\"".to_string();
+    for c in str.chars() {
+        match c {
+            '"' => res += "\\\"",
+            '\\' => res += "\\\\",
+            '\n' => res += "\\n",
+            '\t' => res += "\\t",
+            _ => res.push(c),
+        }
+    }
+    res + "\""
+}
+
+pub fn disassemble(code: &[u8], constants: &[String]) -> String {
+    let instructions = decode(code);
+    // ラベルを付けられるのは命令の先頭かプログラムの末尾だけ
+    let targets: BTreeSet<i64> = (0..instructions.len())
+        .filter_map(|k| jump_target(&instructions, k))
+        .filter(|target| {
+            *target as usize == code.len()
+                || instructions
+                    .iter()
+                    .any(|instruction| instruction.offset as i64 == *target)
+        })
+        .collect();
+
+    let mut res = "".to_string();
+    for (index, str) in constants.iter().enumerate() {
+        res += &format!(
+            "    {:<23} ; constant {}\n",
+            format!(".const {}", quote(str)),
+            index
+        );
+    }
+    for (k, instruction) in instructions.iter().enumerate() {
+        if targets.contains(&(instruction.offset as i64)) {
+            res += &format!("L{}:\n", instruction.offset);
+        }
+        let mut comment = instruction.offset.to_string();
+        let text = match instruction.opcode {
+            Err(byte) => format!(".byte {:#04X}", byte),
+            Ok(opcode) => match instruction.operand {
+                None => opcode.mnemonic().to_string(),
+                Some(operand) => {
+                    let value = i64::from_le_bytes(operand);
+                    // すぐ後のジャンプの飛び先として積む値はラベルで書く
+                    let target = (k + 1 < instructions.len())
+                        .then(|| jump_target(&instructions, k + 1))
+                        .flatten();
+                    // 文字列は中身で書く　ただし同じ文字列が前にもあると添字が変わるので数のまま
+                    let index = value as u64 as usize;
+                    let constant = (opcode == Opecodes::PushStr)
+                        .then(|| constants.get(index))
+                        .flatten()
+                        .filter(|str| constants.iter().position(|c| c == *str) == Some(index));
+                    match (target, constant) {
+                        (Some(target), _) if targets.contains(&target) => {
+                            format!("{} L{}", opcode.mnemonic(), value)
+                        }
+                        (_, Some(str)) => format!("{} {}", opcode.mnemonic(), quote(str)),
+                        _ => format!("{} {}", opcode.mnemonic(), value),
+                    }
+                }
+            },
+        };
+        match instruction.opcode {
+            Ok(Opecodes::PushStr) => {
+                let index = instruction.operand.map(u64::from_le_bytes);
+                if index
+                    .and_then(|index| constants.get(index as usize))
+                    .is_none()
+                {
+                    comment += " invalid constant";
+                }
+            }
+            Ok(Opecodes::Jump | Opecodes::IfNotJump) => match jump_target(&instructions, k) {
+                Some(target) if targets.contains(&target) => comment += &format!(" -> L{}", target),
+                Some(target) => comment += &format!(" -> {} (outside of the program)", target),
+                None => comment += " -> (address on the stack)",
+            },
+            _ => {}
+        }
+        res += &format!("    {:<23} ; {}\n", text, comment);
+    }
+    if targets.contains(&(code.len() as i64)) {
+        res += &format!("L{}:\n", code.len());
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm::assemble;
+    use super::*;
+    use crate::block::compiler::Environment;
+    use crate::block::sexpr;
+
+    fn round_trip(source: &str) {
+        let node = sexpr::read(source).unwrap();
+        let (program, _) = node.compile_program(&mut Environment::default()).unwrap();
+        let text = disassemble(&program.code, &program.constants);
+        let (code, constants) = assemble(&text).unwrap();
+        assert_eq!(code, program.code, "{}", text);
+        assert_eq!(constants, program.constants, "{}", text);
+    }
+
+    #[test]
+    fn round_trips_strings() {
+        round_trip(
+            r#"(list (identifier_list s)
+                 (set s (concat "a;b" "\"quoted\"\n"))
+                 (prints s)
+                 (prints "tab\there")
+                 (prints "a;b"))"#,
+        );
+    }
+
+    #[test]
+    fn round_trips_jumps() {
+        round_trip(
+            r#"(list (identifier_list i)
+                 (set i 0)
+                 (while (lti i 3) (set i (addi i 1)))
+                 (if (eqi i 3) (prints "done") (prints "not done")))"#,
+        );
+    }
+
+    #[test]
+    fn keeps_duplicate_constants() {
+        let code = [Opecodes::PushStr as u8]
+            .into_iter()
+            .chain(1u64.to_le_bytes())
+            .collect::<Vec<u8>>();
+        let constants = vec!["x".to_string(), "x".to_string()];
+        let (assembled, assembled_constants) = assemble(&disassemble(&code, &constants)).unwrap();
+        assert_eq!(assembled, code);
+        assert_eq!(assembled_constants, constants);
+    }
+
+    #[test]
+    fn rejects_const_without_string() {
+        assert!(assemble(".const 3").is_err());
+    }
+}
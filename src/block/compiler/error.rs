@@ -0,0 +1,345 @@
+// パース・コンパイル・実行のエラー
+// どのエラーもDisplayでメッセージになり、分かるときは原因のブロックのidを持つ
+// コマンドラインとエディタはErrorに包んで同じ形で表示する
+
+use super::program::ProgramError;
+use super::verify::VerifyError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    MissingBlock { id: u32, parent: Option<u32> }, // 入力につながっているブロックがない
+    Syntax(String),                                // S式として読めない
+    Cycle(u32),                                    // 入力を辿るとこのブロックに戻ってくる
+}
+
+impl ParseError {
+    // まだ親のブロックが分かっていなければ付ける
+    pub fn at(self, block: u32) -> ParseError {
+        match self {
+            ParseError::MissingBlock { id, parent } => ParseError::MissingBlock {
+                id,
+                parent: parent.or(Some(block)),
+            },
+            _ => self,
+        }
+    }
+
+    pub fn block(&self) -> Option<u32> {
+        match self {
+            ParseError::MissingBlock { parent, .. } => *parent,
+            ParseError::Syntax(_) => None,
+            ParseError::Cycle(id) => Some(*id),
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseError::MissingBlock { id, parent: None } => {
+                write!(f, "block {} does not exist.", id)
+            }
+            ParseError::MissingBlock {
+                id,
+                parent: Some(parent),
+            } => write!(
+                f,
+                "block {} does not exist (input of block {}).",
+                id, parent
+            ),
+            ParseError::Syntax(msg) => write!(f, "{}", msg),
+            ParseError::Cycle(id) => {
+                write!(f, "block {} is connected to its own input.", id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileErrorKind {
+    ArityMismatch {
+        expected: usize,
+        found: usize,
+    },
+    StatementArity {
+        statement: String,
+        expected: usize,
+        found: usize,
+    },
+    TypeMismatch {
+        expected: String,
+        found: String,
+    },
+    UndefinedVariable(String),
+    UnknownStatement(String),
+    UnknownList(String),
+    ExpectedIdentifier,
+    ExpectedIdentifierList,
+    UnknownType(String),
+}
+
+impl std::fmt::Display for CompileErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CompileErrorKind::ArityMismatch { expected, found } => write!(
+                f,
+                "this function takes {} arguments but {} argument was supplied.",
+                expected, found
+            ),
+            CompileErrorKind::StatementArity {
+                statement,
+                expected,
+                found,
+            } => write!(
+                f,
+                "statement '{}' takes {} options but {} options was supplied.",
+                statement, expected, found
+            ),
+            CompileErrorKind::TypeMismatch { expected, found } => {
+                write!(f, "expected type {}, but found type {}.", expected, found)
+            }
+            CompileErrorKind::UndefinedVariable(name) => {
+                write!(f, "variable '{}' is not defined.", name)
+            }
+            CompileErrorKind::UnknownStatement(name) => write!(f, "unknown statement '{}'.", name),
+            CompileErrorKind::UnknownList(name) => write!(f, "unknown list node '{}'.", name),
+            CompileErrorKind::ExpectedIdentifier => write!(f, "expected type was 'identifier'."),
+            CompileErrorKind::ExpectedIdentifierList => {
+                write!(f, "expected block was 'identifier_list'.")
+            }
+            CompileErrorKind::UnknownType(name) => write!(f, "unknown type '{}'.", name),
+        }
+    }
+}
+
+// コンパイルのエラーと、それを起こしたブロック
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub kind: CompileErrorKind,
+    pub block: Option<u32>,
+}
+
+impl CompileError {
+    // まだブロックが分かっていなければ付ける　一番内側のブロックが残る
+    pub fn at(self, block: u32) -> CompileError {
+        CompileError {
+            block: self.block.or(Some(block)),
+            ..self
+        }
+    }
+}
+
+impl From<CompileErrorKind> for CompileError {
+    fn from(kind: CompileErrorKind) -> Self {
+        CompileError { kind, block: None }
+    }
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.block {
+            Some(block) => write!(f, "{} (block {})", self.kind, block),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    StackOverflow,
+    StackUnderflow,
+    FrameOutOfRange(i64),
+    TruncatedOperand(u32),
+    DivisionByZero,
+    InvalidOpcode(u8),
+    ProgramCounterOutOfRange(u32),
+    InvalidConstant(u64),
+    InvalidReference(u64),
+    UnexpectedHeapObject(&'static str),
+    IndexOutOfBounds { index: i64, length: usize },
+    InvalidArrayLength(i64),
+    SubstringOutOfRange { start: i64, length: i64, count: i64 },
+    ExecutionLimitExceeded(u64),
+    Cancelled,
+    InvalidProgram(ProgramError),
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RuntimeError::StackOverflow => write!(f, "stack overflow."),
+            RuntimeError::StackUnderflow => write!(f, "stack underflow."),
+            RuntimeError::FrameOutOfRange(point) => {
+                write!(f, "stack access at {} is out of range.", point)
+            }
+            RuntimeError::TruncatedOperand(pos) => {
+                write!(f, "operand of the instruction at {} is truncated.", pos)
+            }
+            RuntimeError::DivisionByZero => write!(f, "division by zero."),
+            RuntimeError::InvalidOpcode(byte) => write!(f, "invalid opcode {:#X}.", byte),
+            RuntimeError::ProgramCounterOutOfRange(pos) => {
+                write!(f, "jumped outside of the program to {}.", pos)
+            }
+            RuntimeError::InvalidConstant(index) => {
+                write!(f, "invalid constant index {}.", index)
+            }
+            RuntimeError::InvalidReference(handle) => {
+                write!(f, "invalid heap reference {:#X}.", handle)
+            }
+            RuntimeError::UnexpectedHeapObject(expected) => {
+                write!(f, "expected {} on the heap.", expected)
+            }
+            RuntimeError::IndexOutOfBounds { index, length } => write!(
+                f,
+                "index {} is out of bounds for array of length {}.",
+                index, length
+            ),
+            RuntimeError::InvalidArrayLength(length) => {
+                write!(f, "invalid array length {}.", length)
+            }
+            RuntimeError::SubstringOutOfRange {
+                start,
+                length,
+                count,
+            } => write!(
+                f,
+                "substring {}..{} is out of range for length {}.",
+                start,
+                start.saturating_add(*length),
+                count
+            ),
+            RuntimeError::ExecutionLimitExceeded(steps) => {
+                write!(f, "execution limit exceeded ({} steps).", steps)
+            }
+            RuntimeError::Cancelled => write!(f, "execution was cancelled."),
+            RuntimeError::InvalidProgram(err) => write!(f, "invalid program: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RuntimeError::InvalidProgram(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// 実行時エラーと、それが起きた命令の位置とその命令を作ったブロック
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmError {
+    pub error: RuntimeError,
+    pub offset: Option<u32>,
+    pub block: Option<u32>,
+}
+
+impl std::fmt::Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.block, self.offset) {
+            (Some(block), _) => write!(f, "{} (block {})", self.error, block),
+            (None, Some(offset)) => write!(f, "{} (at {})", self.error, offset),
+            (None, None) => write!(f, "{}", self.error),
+        }
+    }
+}
+
+impl std::error::Error for VmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+// コマンドラインとエディタに出すエラー　先頭にどの段階のエラーかが付く
+#[derive(Debug)]
+pub enum Error {
+    Open(String),
+    Parse(ParseError),
+    Compile(Vec<CompileError>), // 一度のコンパイルで見つかったすべてのエラー
+    Assemble(String),
+    Load(ProgramError),
+    Verify(VerifyError),
+    Runtime(VmError),
+    Write(String),
+}
+
+impl Error {
+    // エラーを起こしたブロックと、段階やブロックを付けないメッセージ
+    pub fn blocks(&self) -> Vec<(u32, String)> {
+        match self {
+            Error::Parse(err) => err
+                .block()
+                .map(|block| (block, err.to_string()))
+                .into_iter()
+                .collect(),
+            Error::Compile(errs) => errs
+                .iter()
+                .filter_map(|err| err.block.map(|block| (block, err.kind.to_string())))
+                .collect(),
+            Error::Runtime(err) => err
+                .block
+                .map(|block| (block, err.error.to_string()))
+                .into_iter()
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Open(msg) => write!(f, "OpenError:{}", msg),
+            Error::Parse(err) => write!(f, "ParseError:{}", err),
+            Error::Compile(errs) => {
+                let lines: Vec<String> = errs
+                    .iter()
+                    .map(|err| format!("CompileError:{}", err))
+                    .collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Error::Assemble(msg) => write!(f, "AssembleError:{}", msg),
+            Error::Load(err) => write!(f, "LoadError:{}", err),
+            Error::Verify(err) => write!(f, "VerifyError:{}", err),
+            Error::Runtime(err) => write!(f, "RuntimeError:{}", err),
+            Error::Write(msg) => write!(f, "WriteError:{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Parse(err) => Some(err),
+            Error::Compile(errs) => errs
+                .first()
+                .map(|err| err as &(dyn std::error::Error + 'static)),
+            Error::Load(err) => Some(err),
+            Error::Verify(err) => Some(err),
+            Error::Runtime(err) => Some(err),
+            Error::Open(_) | Error::Assemble(_) | Error::Write(_) => None,
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Self {
+        Error::Parse(err)
+    }
+}
+
+impl From<Vec<CompileError>> for Error {
+    fn from(errs: Vec<CompileError>) -> Self {
+        Error::Compile(errs)
+    }
+}
+
+impl From<VmError> for Error {
+    fn from(err: VmError) -> Self {
+        Error::Runtime(err)
+    }
+}
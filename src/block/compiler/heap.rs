@@ -0,0 +1,204 @@
+// VMのヒープ　スタックには8バイトのハンドル（objectsの添字）だけを積む
+// スタックや配列の各値にはヒープへの参照かどうかのタグが付いていて、
+// それをルートにマーク&スイープでGCする
+// GCするかどうかはオブジェクトの数ではなく使っているバイト数で決める
+
+use super::{RuntimeError, MAX_ARRAY_LENGTH};
+
+const FIRST_GC_THRESHOLD: usize = 1 << 20; // バイト
+const OBJECT_HEADER_SIZE: usize = 32; // 中身の他にオブジェクト一つにかかる分の見積もり
+const ELEMENT_SIZE: usize = std::mem::size_of::<([u8; 8], bool)>();
+
+pub enum HeapObject {
+    Str(String),
+    Array(Vec<([u8; 8], bool)>), // (値, ヒープへの参照か)
+}
+
+pub struct Heap {
+    objects: Vec<Option<HeapObject>>,
+    free: Vec<usize>,
+    bytes: usize, // 生きているオブジェクトの大きさの合計
+    threshold: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap {
+            objects: vec![],
+            free: vec![],
+            bytes: 0,
+            threshold: FIRST_GC_THRESHOLD,
+        }
+    }
+}
+
+impl HeapObject {
+    fn size(&self) -> usize {
+        OBJECT_HEADER_SIZE
+            + match self {
+                HeapObject::Str(str) => str.len(),
+                HeapObject::Array(array) => array.len() * ELEMENT_SIZE,
+            }
+    }
+}
+
+impl Heap {
+    pub fn alloc(&mut self, object: HeapObject) -> [u8; 8] {
+        self.bytes += object.size();
+        match self.free.pop() {
+            Some(index) => {
+                self.objects[index] = Some(object);
+                (index as u64).to_le_bytes()
+            }
+            None => {
+                self.objects.push(Some(object));
+                ((self.objects.len() - 1) as u64).to_le_bytes()
+            }
+        }
+    }
+    pub fn get(&self, handle: [u8; 8]) -> Result<&HeapObject, RuntimeError> {
+        match self.objects.get(u64::from_le_bytes(handle) as usize) {
+            Some(Some(object)) => Ok(object),
+            _ => Err(RuntimeError::InvalidReference(u64::from_le_bytes(handle))),
+        }
+    }
+    pub fn get_str(&self, handle: [u8; 8]) -> Result<&String, RuntimeError> {
+        match self.get(handle)? {
+            HeapObject::Str(str) => Ok(str),
+            _ => Err(RuntimeError::UnexpectedHeapObject("a string")),
+        }
+    }
+    pub fn get_array(&self, handle: [u8; 8]) -> Result<&Vec<([u8; 8], bool)>, RuntimeError> {
+        match self.get(handle)? {
+            HeapObject::Array(array) => Ok(array),
+            _ => Err(RuntimeError::UnexpectedHeapObject("an array")),
+        }
+    }
+    pub fn get_array_mut(
+        &mut self,
+        handle: [u8; 8],
+    ) -> Result<&mut Vec<([u8; 8], bool)>, RuntimeError> {
+        match self.objects.get_mut(u64::from_le_bytes(handle) as usize) {
+            Some(Some(HeapObject::Array(array))) => Ok(array),
+            Some(Some(_)) => Err(RuntimeError::UnexpectedHeapObject("an array")),
+            _ => Err(RuntimeError::InvalidReference(u64::from_le_bytes(handle))),
+        }
+    }
+    // 配列の末尾に追加する　大きくなった分を数えるのでget_array_mutを使わない
+    // make-arrayと同じ長さまでしか伸ばせない
+    pub fn push(&mut self, handle: [u8; 8], value: ([u8; 8], bool)) -> Result<(), RuntimeError> {
+        let array = self.get_array_mut(handle)?;
+        if array.len() >= MAX_ARRAY_LENGTH {
+            return Err(RuntimeError::InvalidArrayLength(array.len() as i64 + 1));
+        }
+        array.push(value);
+        self.bytes += ELEMENT_SIZE;
+        Ok(())
+    }
+    // 文字列の文字数、または配列の要素数
+    pub fn length(&self, handle: [u8; 8]) -> Result<usize, RuntimeError> {
+        match self.get(handle)? {
+            HeapObject::Str(str) => Ok(str.chars().count()),
+            HeapObject::Array(array) => Ok(array.len()),
+        }
+    }
+
+    pub fn needs_collect(&self) -> bool {
+        self.bytes >= self.threshold
+    }
+    // rootsから辿れないオブジェクトを解放する
+    pub fn collect(&mut self, roots: impl Iterator<Item = [u8; 8]>) {
+        let mut marked = vec![false; self.objects.len()];
+        let mut work: Vec<usize> = roots.map(|r| u64::from_le_bytes(r) as usize).collect();
+        while let Some(index) = work.pop() {
+            if index >= marked.len() || marked[index] {
+                continue;
+            }
+            marked[index] = true;
+            if let Some(HeapObject::Array(array)) = &self.objects[index] {
+                for (value, is_ref) in array.iter() {
+                    if *is_ref {
+                        work.push(u64::from_le_bytes(*value) as usize);
+                    }
+                }
+            }
+        }
+
+        for (index, object) in self.objects.iter_mut().enumerate() {
+            if !marked[index] {
+                if let Some(freed) = object.take() {
+                    self.bytes -= freed.size();
+                    self.free.push(index);
+                }
+            }
+        }
+        self.threshold = (self.bytes * 2).max(FIRST_GC_THRESHOLD);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(heap: &mut Heap, str: &str) -> [u8; 8] {
+        heap.alloc(HeapObject::Str(str.to_string()))
+    }
+
+    #[test]
+    fn frees_unreachable_objects() {
+        let mut heap = Heap::default();
+        let kept = string(&mut heap, "kept");
+        let dropped = string(&mut heap, "dropped");
+        heap.collect([kept].into_iter());
+        assert_eq!(heap.get_str(kept).unwrap(), "kept");
+        assert!(matches!(
+            heap.get(dropped),
+            Err(RuntimeError::InvalidReference(_))
+        ));
+    }
+
+    #[test]
+    fn keeps_objects_reachable_through_arrays() {
+        let mut heap = Heap::default();
+        let inner = string(&mut heap, "inner");
+        let array = heap.alloc(HeapObject::Array(vec![]));
+        heap.push(array, (inner, true)).unwrap();
+        // 参照でない値はハンドルと同じビットでも辿らない
+        let number = string(&mut heap, "number");
+        heap.push(array, (number, false)).unwrap();
+        heap.collect([array].into_iter());
+        assert_eq!(heap.get_str(inner).unwrap(), "inner");
+        assert_eq!(heap.get_array(array).unwrap().len(), 2);
+        assert!(heap.get(number).is_err());
+    }
+
+    #[test]
+    fn reuses_freed_slots() {
+        let mut heap = Heap::default();
+        let dropped = string(&mut heap, "dropped");
+        heap.collect(std::iter::empty());
+        assert_eq!(string(&mut heap, "new"), dropped);
+    }
+
+    #[test]
+    fn collects_when_bytes_reach_the_threshold() {
+        let mut heap = Heap::default();
+        let big = heap.alloc(HeapObject::Str("x".repeat(FIRST_GC_THRESHOLD)));
+        assert!(heap.needs_collect());
+        heap.collect([big].into_iter());
+        // 生きている分の2倍までは集めない
+        assert!(!heap.needs_collect());
+        heap.collect(std::iter::empty());
+        assert_eq!(heap.bytes, 0);
+        assert_eq!(heap.threshold, FIRST_GC_THRESHOLD);
+    }
+
+    #[test]
+    fn counts_pushed_elements() {
+        let mut heap = Heap::default();
+        let array = heap.alloc(HeapObject::Array(vec![]));
+        let before = heap.bytes;
+        heap.push(array, ([0; 8], false)).unwrap();
+        assert_eq!(heap.bytes, before + ELEMENT_SIZE);
+    }
+}
@@ -0,0 +1,593 @@
+// コード生成の前にAstNode全体の型を推論する
+// 型の分からないところは型変数にしておき、使われ方から単一化で決めていく
+// 変数は書き換えられるので、let多相のような一般化はしない
+// ifの二つの枝の型が合わないときは、今までどおりエラーにせず何の型でもよいことにする
+// 自分自身を含む型（関数を自分に渡す再帰など）も表せないので、エラーにせず決めないでおく
+
+use super::*;
+use std::collections::HashMap;
+
+// 推論の結果　typesはブロックごとの型
+pub struct Inference {
+    pub ty: Type,
+    pub types: HashMap<u32, Type>,
+    pub errors: Vec<CompileError>,
+}
+
+// identifier_listの変数と、lambdaの戻り値の型注釈
+struct Bindings {
+    variables: Vec<(String, Type)>,
+    ret: Option<Type>,
+}
+
+#[derive(Default)]
+struct Inferer {
+    substitution: Vec<Option<Type>>, // 型変数ごとに決まった型
+    scopes: Vec<HashMap<String, Type>>,
+    block: Option<u32>, // 今見ているノードのブロック
+    types: HashMap<u32, Type>,
+    errors: Vec<CompileError>,
+    lengths: Vec<(Option<u32>, Type)>, // 型が決まるまで調べるのを待つlengthの引数
+}
+
+impl Inferer {
+    fn fresh(&mut self) -> Type {
+        self.substitution.push(None);
+        Type::Var(self.substitution.len() as u32 - 1)
+    }
+
+    // 型変数が決まっていれば中身に置き換える　一番外側だけ
+    fn prune(&self, ty: &Type) -> Type {
+        match ty {
+            Type::Var(n) => match &self.substitution[*n as usize] {
+                Some(ty) => self.prune(ty),
+                None => ty.clone(),
+            },
+            _ => ty.clone(),
+        }
+    }
+
+    // 決まっている型変数をすべて置き換える
+    fn resolve(&self, ty: &Type) -> Type {
+        match self.prune(ty) {
+            Type::Array(element) => Type::Array(Box::new(self.resolve(&element))),
+            Type::Function(params, ret) => Type::Function(
+                params.iter().map(|param| self.resolve(param)).collect(),
+                Box::new(self.resolve(&ret)),
+            ),
+            ty => ty,
+        }
+    }
+
+    fn occurs(&self, n: u32, ty: &Type) -> bool {
+        match self.prune(ty) {
+            Type::Var(m) => n == m,
+            Type::Array(element) => self.occurs(n, &element),
+            Type::Function(params, ret) => {
+                params.iter().any(|param| self.occurs(n, param)) || self.occurs(n, &ret)
+            }
+            _ => false,
+        }
+    }
+
+    fn unify(&mut self, a: &Type, b: &Type) -> bool {
+        match (self.prune(a), self.prune(b)) {
+            (Type::Var(n), Type::Var(m)) if n == m => true,
+            (Type::Var(n), ty) | (ty, Type::Var(n)) => {
+                if !self.occurs(n, &ty) {
+                    self.substitution[n as usize] = Some(ty);
+                }
+                true
+            }
+            (Type::Array(a), Type::Array(b)) => self.unify(&a, &b),
+            (Type::Function(params1, ret1), Type::Function(params2, ret2)) => {
+                params1.len() == params2.len()
+                    && params1
+                        .iter()
+                        .zip(params2.iter())
+                        .all(|(a, b)| self.unify(a, b))
+                    && self.unify(&ret1, &ret2)
+            }
+            (a, b) => a == b,
+        }
+    }
+
+    // 失敗したら何も変えない
+    fn try_unify(&mut self, a: &Type, b: &Type) -> bool {
+        let saved = self.substitution.clone();
+        let res = self.unify(a, b);
+        if !res {
+            self.substitution = saved;
+        }
+        res
+    }
+
+    fn report(&mut self, kind: CompileErrorKind) {
+        self.errors.push(CompileError {
+            kind,
+            block: self.block,
+        });
+    }
+
+    fn expect(&mut self, expected: &Type, found: &Type) {
+        if !self.unify(expected, found) {
+            let kind = CompileErrorKind::TypeMismatch {
+                expected: self.resolve(expected).to_string(),
+                found: self.resolve(found).to_string(),
+            };
+            self.report(kind);
+        }
+    }
+
+    // 食い違いをnodeのブロックで報告する
+    fn expect_at(&mut self, node: &AstNode, expected: &Type, found: &Type) {
+        let outer = self.block;
+        if let AstNode::Located { block, .. } = node {
+            self.block = Some(*block);
+        }
+        self.expect(expected, found);
+        self.block = outer;
+    }
+
+    // 式としては辿らない変数のブロックにも型を付ける
+    fn record(&mut self, node: &AstNode, ty: &Type) {
+        if let AstNode::Located { block, .. } = node {
+            self.types.insert(*block, ty.clone());
+        }
+    }
+
+    fn lookup(&mut self, name: &str) -> Type {
+        for scope in self.scopes.iter().rev() {
+            if let Some(ty) = scope.get(name) {
+                return ty.clone();
+            }
+        }
+        self.report(CompileErrorKind::UndefinedVariable(name.to_string()));
+        self.fresh()
+    }
+
+    // 型注釈の型　Unknownは新しい型変数にする
+    fn instantiate(&mut self, t: &Type) -> Type {
+        match t {
+            Type::Function(params, ret) => Type::Function(
+                params.iter().map(|param| self.instantiate(param)).collect(),
+                Box::new(self.instantiate(ret)),
+            ),
+            Type::Array(element) => Type::Array(Box::new(self.instantiate(element))),
+            Type::Unknown => self.fresh(),
+            t => t.clone(),
+        }
+    }
+
+    // identifier_listなら、変数を書いた順に型注釈の型か新しい型変数を割り当てる
+    // 戻り値の型注釈（->integer）があればその型も返す
+    fn bindings(&mut self, node: &AstNode) -> Option<Bindings> {
+        let AstNode::List { name, codes } = node.inner() else {
+            return None;
+        };
+        if name != "identifier_list" {
+            return None;
+        }
+        let mut variables: Vec<(String, Type)> = vec![];
+        let mut ret = None;
+        for code in codes {
+            let AstNode::Identifier(id) = code.inner() else {
+                return None;
+            };
+            // 読めない型注釈はコード生成でエラーになるので、ここでは型の分からない変数にする
+            let (name, t) = split_annotation(id).unwrap_or_else(|_| match id.split_once(':') {
+                Some((name, _)) if !id.starts_with("->") => (name, Type::Unknown),
+                _ => ("", Type::Unknown),
+            });
+            let ty = self.instantiate(&t);
+            self.record(code, &ty);
+            if name.is_empty() {
+                ret = Some(ty);
+            } else {
+                variables.push((name.to_string(), ty));
+            }
+        }
+        Some(Bindings { variables, ret })
+    }
+
+    fn infer(&mut self, node: &AstNode) -> Type {
+        match node {
+            AstNode::Located { block, node } => {
+                let outer = self.block.replace(*block);
+                let ty = self.infer(node);
+                self.block = outer;
+                self.types.insert(*block, ty.clone());
+                ty
+            }
+            AstNode::ValueInteger(_) => Type::Integer,
+            AstNode::ValueFloat(_) => Type::Float,
+            AstNode::ValueStr(_) => Type::String,
+            AstNode::ValueBool(_) => Type::Bool,
+            AstNode::Identifier(name) => self.lookup(name),
+            AstNode::Statement { statement, options } => self.infer_statement(statement, options),
+            AstNode::List { name, codes } if name == "list" => {
+                let bindings = codes.first().and_then(|first| self.bindings(first));
+                let start = bindings.is_some() as usize;
+                let scope = bindings.map(|bindings| bindings.variables.into_iter().collect());
+                self.scopes.push(scope.unwrap_or_default());
+                let mut ty = Type::Integer; // 式がない場合は0
+                for code in codes.iter().skip(start) {
+                    ty = self.infer(code);
+                }
+                self.scopes.pop();
+                ty
+            }
+            AstNode::List { .. } => self.fresh(), // コード生成でエラーになる
+            AstNode::Function { func, args } => self.infer_call(func, args),
+        }
+    }
+
+    fn infer_statement(&mut self, statement: &str, options: &[AstNode]) -> Type {
+        match (statement, options) {
+            ("set", [variable, exp]) => {
+                let ty = self.infer(exp);
+                if let AstNode::Identifier(name) = variable.inner() {
+                    let var = self.lookup(name);
+                    self.record(variable, &var);
+                    self.expect(&var, &ty);
+                }
+                ty
+            }
+            ("lambda", [first, rest @ ..]) if rest.len() <= 1 => match self.bindings(first) {
+                Some(Bindings {
+                    variables,
+                    ret: annotation,
+                }) => {
+                    let params: Vec<Type> = variables.iter().map(|(_, ty)| ty.clone()).collect();
+                    self.scopes.push(variables.into_iter().collect());
+                    let mut ret = match rest {
+                        [body] => self.infer(body),
+                        _ => self.fresh(), // 本体がないのはコード生成でエラーになる
+                    };
+                    self.scopes.pop();
+                    if let Some(annotation) = annotation {
+                        self.expect(&annotation, &ret);
+                        ret = annotation;
+                    }
+                    Type::Function(params, Box::new(ret))
+                }
+                // 引数がなければ最初のオプションが本体
+                None => {
+                    let ret = self.infer(first);
+                    Type::Function(vec![], Box::new(ret))
+                }
+            },
+            ("if", [condition, then, otherwise]) => {
+                self.infer_condition(condition);
+                let a = self.infer(then);
+                let b = self.infer(otherwise);
+                if self.try_unify(&a, &b) {
+                    a
+                } else {
+                    self.fresh()
+                }
+            }
+            ("while", [condition, body]) => {
+                self.infer_condition(condition);
+                self.infer(body);
+                Type::Integer
+            }
+            // 数が合わないものはコード生成でエラーになる
+            _ => {
+                for option in options {
+                    self.infer(option);
+                }
+                self.fresh()
+            }
+        }
+    }
+
+    // 条件はboolean（互換性のためintegerも許可）
+    // まだ型の決まっていない変数はどちらにもなれるので決めない
+    fn infer_condition(&mut self, condition: &AstNode) {
+        let ty = self.infer(condition);
+        match self.prune(&ty) {
+            Type::Integer | Type::Bool | Type::Var(_) => {}
+            _ => self.expect(&Type::Bool, &ty),
+        }
+    }
+
+    fn infer_call(&mut self, func: &str, args: &[AstNode]) -> Type {
+        let types: Vec<Type> = args.iter().map(|arg| self.infer(arg)).collect();
+        if func == "length" {
+            // 文字列か配列　まだ決まっていなければ最後まで待つ
+            if let ([arg], [ty]) = (args, types.as_slice()) {
+                let block = match arg {
+                    AstNode::Located { block, .. } => Some(*block),
+                    _ => self.block,
+                };
+                self.lengths.push((block, ty.clone()));
+            }
+            return Type::Integer;
+        }
+        let (params, ret) = match self.builtin(func) {
+            Some(signature) => signature,
+            // 変数に入っている関数を呼ぶ
+            // 型の分かっている関数なら組み込み関数と同じく引数ごとに調べる
+            None => {
+                let callee = self.lookup(func);
+                match self.prune(&callee) {
+                    Type::Function(params, ret) => (params, *ret),
+                    _ => {
+                        let ret = self.fresh();
+                        let ty = Type::Function(types.clone(), Box::new(ret.clone()));
+                        self.expect(&callee, &ty);
+                        return ret;
+                    }
+                }
+            }
+        };
+        // 数が違うとフレームが壊れるので、型の分からない関数として通さずここで報告する
+        if params.len() != types.len() {
+            self.report(CompileErrorKind::ArityMismatch {
+                expected: params.len(),
+                found: types.len(),
+            });
+            return self.fresh();
+        }
+        for ((param, ty), arg) in params.iter().zip(types.iter()).zip(args) {
+            self.expect_at(arg, param, ty);
+        }
+        ret
+    }
+
+    // lengthの引数が文字列でも配列でもなければ報告する　最後まで決まらなければどちらでもよい
+    fn check_lengths(&mut self) {
+        for (block, ty) in std::mem::take(&mut self.lengths) {
+            match self.resolve(&ty) {
+                Type::String | Type::Array(_) | Type::Var(_) => {}
+                found => self.errors.push(CompileError {
+                    kind: CompileErrorKind::TypeMismatch {
+                        expected: "string or array".to_string(),
+                        found: found.to_string(),
+                    },
+                    block,
+                }),
+            }
+        }
+    }
+
+    // 組み込み関数の引数と戻り値の型
+    fn builtin(&mut self, func: &str) -> Option<(Vec<Type>, Type)> {
+        let (i, f, s, b) = (Type::Integer, Type::Float, Type::String, Type::Bool);
+        let signature = match func {
+            "addi" | "subi" | "muli" | "divi" | "modi" => (vec![i.clone(), i.clone()], i),
+            "addf" | "subf" | "mulf" | "divf" | "modf" => (vec![f.clone(), f.clone()], f),
+            "eqi" | "nei" | "lti" | "gti" | "lei" | "gei" => (vec![i.clone(), i], b),
+            "eqf" | "nef" | "ltf" | "gtf" | "lef" | "gef" => (vec![f.clone(), f], b),
+            "and" | "or" => (vec![b.clone(), b.clone()], b),
+            "not" => (vec![b.clone()], b),
+            "printi" => (vec![i.clone()], i),
+            "printf" => (vec![f.clone()], f),
+            "prints" => (vec![s.clone()], s),
+            "concat" => (vec![s.clone(), s.clone()], s),
+            "eqs" => (vec![s.clone(), s], b),
+            "substring" => (vec![s.clone(), i.clone(), i], s),
+            "make-array" => {
+                let element = self.fresh();
+                (vec![i, element.clone()], Type::Array(Box::new(element)))
+            }
+            "get" => {
+                let element = self.fresh();
+                (vec![Type::Array(Box::new(element.clone())), i], element)
+            }
+            "set" => {
+                let element = self.fresh();
+                (
+                    vec![Type::Array(Box::new(element.clone())), i, element.clone()],
+                    element,
+                )
+            }
+            "push" => {
+                let element = self.fresh();
+                let array = Type::Array(Box::new(element.clone()));
+                (vec![array.clone(), element], array)
+            }
+            _ => return None,
+        };
+        Some(signature)
+    }
+}
+
+pub fn infer(node: &AstNode) -> Inference {
+    let mut inferer = Inferer::default();
+    let ty = inferer.infer(node);
+    inferer.check_lengths();
+    Inference {
+        ty: inferer.resolve(&ty),
+        types: inferer
+            .types
+            .iter()
+            .map(|(block, ty)| (*block, inferer.resolve(ty)))
+            .collect(),
+        errors: inferer.errors,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::sexpr;
+
+    fn infer_source(source: &str) -> Inference {
+        infer(&sexpr::read(source).unwrap())
+    }
+
+    fn located(block: u32, node: AstNode) -> AstNode {
+        AstNode::Located {
+            block,
+            node: Box::new(node),
+        }
+    }
+
+    fn call(func: &str, args: Vec<AstNode>) -> AstNode {
+        AstNode::Function {
+            func: func.to_string(),
+            args,
+        }
+    }
+
+    #[test]
+    fn infers_types_from_usage() {
+        let inference = infer_source(
+            "(list (identifier_list f) (set f (lambda (identifier_list n) (muli n 2))) f)",
+        );
+        assert!(inference.errors.is_empty());
+        assert_eq!(inference.ty.to_string(), "(integer) -> integer");
+    }
+
+    #[test]
+    fn leaves_unused_types_open() {
+        let inference = infer_source("(lambda (identifier_list x) x)");
+        assert!(inference.errors.is_empty());
+        assert_eq!(inference.ty.to_string(), "('a) -> 'a");
+    }
+
+    #[test]
+    fn infers_array_elements() {
+        let inference =
+            infer_source("(list (identifier_list a) (set a (make-array 3 0.5)) (get a 1))");
+        assert!(inference.errors.is_empty());
+        assert_eq!(inference.ty.to_string(), "float");
+    }
+
+    #[test]
+    fn reports_undefined_variables() {
+        let inference = infer_source("(printi x)");
+        assert_eq!(
+            inference.errors[0].kind,
+            CompileErrorKind::UndefinedVariable("x".to_string())
+        );
+    }
+
+    #[test]
+    fn reports_each_argument_at_its_block() {
+        // (printi (addf 1 0))
+        let node = located(
+            1,
+            call(
+                "printi",
+                vec![located(
+                    2,
+                    call(
+                        "addf",
+                        vec![
+                            located(3, AstNode::ValueInteger(1)),
+                            located(4, AstNode::ValueInteger(0)),
+                        ],
+                    ),
+                )],
+            ),
+        );
+        let blocks: Vec<Option<u32>> = infer(&node).errors.iter().map(|err| err.block).collect();
+        assert_eq!(blocks, vec![Some(3), Some(4), Some(2)]);
+    }
+
+    #[test]
+    fn keeps_variables_with_bad_annotations() {
+        let node =
+            sexpr::read("(list (identifier_list a n:intger) (set a 1) (set n 2) (printi a))")
+                .unwrap();
+        assert!(infer(&node).errors.is_empty());
+        let errors = node
+            .compile_program(&mut Environment::default())
+            .err()
+            .unwrap();
+        let kinds: Vec<CompileErrorKind> = errors.into_iter().map(|err| err.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![CompileErrorKind::UnknownType("intger".to_string())]
+        );
+    }
+
+    #[test]
+    fn allows_passing_a_function_to_itself() {
+        // READMEの例3
+        let source = "(list (identifier_list f) (set f (lambda (identifier_list n g) (if (eqi n 0) 0 (if (eqi n 1) 1 (addi (g (subi n 2) g) (g (subi n 1) g)))))) (printi (f 10 f)))";
+        let node = sexpr::read(source).unwrap();
+        assert!(infer(&node).errors.is_empty());
+        let (program, _) = node.compile_program(&mut Environment::default()).unwrap();
+        let mut lines: Vec<String> = vec![];
+        execute_vm(&program.to_bytes(), &VmOptions::default(), &mut |line| {
+            lines.push(line)
+        })
+        .unwrap();
+        assert_eq!(lines, vec!["55".to_string()]);
+    }
+
+    #[test]
+    fn waits_for_the_argument_of_length() {
+        // lengthのあとで文字列として使う
+        let source = "(list (identifier_list f) (set f (lambda (identifier_list s) (list (printi (length s)) (prints s)))) (f \"hey\"))";
+        let node = sexpr::read(source).unwrap();
+        assert!(infer(&node).errors.is_empty());
+        let (program, _) = node.compile_program(&mut Environment::default()).unwrap();
+        let mut lines: Vec<String> = vec![];
+        execute_vm(&program.to_bytes(), &VmOptions::default(), &mut |line| {
+            lines.push(line)
+        })
+        .unwrap();
+        assert_eq!(lines, vec!["3".to_string(), "hey".to_string()]);
+
+        let inference = infer_source("(lambda (identifier_list a) (list (length a) (get a 0)))");
+        assert!(inference.errors.is_empty());
+        let inference = infer_source("(lambda (identifier_list n) (list (length n) (addi n 1)))");
+        assert_eq!(
+            inference.errors[0].kind,
+            CompileErrorKind::TypeMismatch {
+                expected: "string or array".to_string(),
+                found: "integer".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn reports_calls_with_the_wrong_number_of_arguments() {
+        // 関数として渡されたgを、一度は2引数、一度は1引数で呼ぶ
+        let source = "(list (identifier_list h) (set h (lambda (identifier_list g) (list (g 1 2) (g 5)))) (printi (h (lambda (identifier_list a b) (addi a b)))))";
+        let node = sexpr::read(source).unwrap();
+        let errors = node
+            .compile_program(&mut Environment::default())
+            .err()
+            .unwrap();
+        let kinds: Vec<CompileErrorKind> = errors.into_iter().map(|err| err.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![CompileErrorKind::ArityMismatch {
+                expected: 2,
+                found: 1
+            }]
+        );
+
+        // (printi 1 2) コード生成でも止まるが一度だけ報告する
+        let node = located(
+            1,
+            call(
+                "printi",
+                vec![
+                    located(2, AstNode::ValueInteger(1)),
+                    located(3, AstNode::ValueInteger(2)),
+                ],
+            ),
+        );
+        let errors = node
+            .compile_program(&mut Environment::default())
+            .err()
+            .unwrap();
+        assert_eq!(
+            errors,
+            vec![CompileError {
+                kind: CompileErrorKind::ArityMismatch {
+                    expected: 1,
+                    found: 2
+                },
+                block: Some(1),
+            }]
+        );
+    }
+}
@@ -0,0 +1,332 @@
+// コンパイル済みプログラムの入れ物　ファイルに保存してそのまま実行できる
+// "NDSP" | version u16 | flags u16 | entry u32 | code | constants | (debug)
+// codeとdebugはu32の長さ付きのバイト列、constantsはu32の個数のあとに長さ付きのUTF-8文字列が続く
+// debugの中身はソースマップで、u32の個数のあとに (start u32, end u32, block u32) が並ぶ
+// 数値はすべてリトルエンディアン
+
+pub const MAGIC: [u8; 4] = *b"NDSP";
+pub const FORMAT_VERSION: u16 = 1;
+const FLAG_DEBUG: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub entry: u32,
+    pub code: Vec<u8>,
+    pub constants: Vec<String>,
+    pub debug: Option<SourceMap>,
+}
+
+// バイトコードのstart..endがどのブロックから作られたか
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourceRange {
+    pub start: u32,
+    pub end: u32,
+    pub block: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SourceMap {
+    pub ranges: Vec<SourceRange>,
+}
+
+impl SourceMap {
+    // offsetを含む範囲のうち一番狭いもの、つまり一番内側のブロック
+    pub fn block_at(&self, offset: u32) -> Option<u32> {
+        self.ranges
+            .iter()
+            .filter(|range| range.start <= offset && offset < range.end)
+            .min_by_key(|range| range.end - range.start)
+            .map(|range| range.block)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut res: Vec<u8> = (self.ranges.len() as u32).to_le_bytes().to_vec();
+        for range in self.ranges.iter() {
+            res.extend(range.start.to_le_bytes());
+            res.extend(range.end.to_le_bytes());
+            res.extend(range.block.to_le_bytes());
+        }
+        res
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<SourceMap, ProgramError> {
+        let mut reader = Reader { bytes, pos: 0 };
+        let count = reader.u32("debug")?;
+        let mut ranges: Vec<SourceRange> = vec![];
+        for _ in 0..count {
+            let start = reader.u32("debug")?;
+            let end = reader.u32("debug")?;
+            let block = reader.u32("debug")?;
+            if start > end {
+                return Err(ProgramError::InvalidSourceMap);
+            }
+            ranges.push(SourceRange { start, end, block });
+        }
+        if reader.pos != bytes.len() {
+            return Err(ProgramError::InvalidSourceMap);
+        }
+        Ok(SourceMap { ranges })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgramError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnknownFlags(u16),
+    Truncated(&'static str),
+    TrailingBytes(usize),
+    EntryOutOfRange(u32),
+    InvalidConstant(u32),
+    InvalidSourceMap,
+}
+
+impl std::fmt::Display for ProgramError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ProgramError::BadMagic => write!(f, "not a nodisp program."),
+            ProgramError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported program version {} (expected {}).",
+                version, FORMAT_VERSION
+            ),
+            ProgramError::UnknownFlags(flags) => write!(f, "unknown program flags {:#X}.", flags),
+            ProgramError::Truncated(section) => write!(f, "program is truncated in {}.", section),
+            ProgramError::TrailingBytes(count) => {
+                write!(f, "{} unexpected bytes after the program.", count)
+            }
+            ProgramError::EntryOutOfRange(entry) => {
+                write!(f, "entry point {} is outside of the code.", entry)
+            }
+            ProgramError::InvalidConstant(index) => {
+                write!(f, "constant {} is not valid UTF-8.", index)
+            }
+            ProgramError::InvalidSourceMap => write!(f, "broken source map."),
+        }
+    }
+}
+
+impl std::error::Error for ProgramError {}
+
+// 先頭から順に読んでいくためのカーソル
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize, section: &'static str) -> Result<&'a [u8], ProgramError> {
+        let slice = self
+            .bytes
+            .get(self.pos..self.pos.saturating_add(count))
+            .ok_or(ProgramError::Truncated(section))?;
+        self.pos += count;
+        Ok(slice)
+    }
+    fn u16(&mut self, section: &'static str) -> Result<u16, ProgramError> {
+        let bytes = self.take(2, section)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+    fn u32(&mut self, section: &'static str) -> Result<u32, ProgramError> {
+        let bytes = self.take(4, section)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+    fn block(&mut self, section: &'static str) -> Result<&'a [u8], ProgramError> {
+        let len = self.u32(section)? as usize;
+        self.take(len, section)
+    }
+}
+
+fn push_block(res: &mut Vec<u8>, bytes: &[u8]) {
+    res.extend((bytes.len() as u32).to_le_bytes());
+    res.extend(bytes);
+}
+
+impl Program {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res: Vec<u8> = MAGIC.to_vec();
+        res.extend(FORMAT_VERSION.to_le_bytes());
+        let flags = if self.debug.is_some() { FLAG_DEBUG } else { 0 };
+        res.extend(flags.to_le_bytes());
+        res.extend(self.entry.to_le_bytes());
+        push_block(&mut res, &self.code);
+        res.extend((self.constants.len() as u32).to_le_bytes());
+        for constant in self.constants.iter() {
+            push_block(&mut res, constant.as_bytes());
+        }
+        if let Some(debug) = &self.debug {
+            push_block(&mut res, &debug.to_bytes());
+        }
+        res
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, ProgramError> {
+        let mut reader = Reader { bytes, pos: 0 };
+        if reader.take(4, "header")? != MAGIC {
+            return Err(ProgramError::BadMagic);
+        }
+        let version = reader.u16("header")?;
+        if version != FORMAT_VERSION {
+            return Err(ProgramError::UnsupportedVersion(version));
+        }
+        let flags = reader.u16("header")?;
+        if flags & !FLAG_DEBUG != 0 {
+            return Err(ProgramError::UnknownFlags(flags));
+        }
+        let entry = reader.u32("header")?;
+        let code = reader.block("code")?.to_vec();
+        if entry as usize >= code.len() {
+            return Err(ProgramError::EntryOutOfRange(entry));
+        }
+        let count = reader.u32("constants")?;
+        let mut constants: Vec<String> = vec![];
+        for index in 0..count {
+            let bytes = reader.block("constants")?;
+            match std::str::from_utf8(bytes) {
+                Ok(str) => constants.push(str.to_string()),
+                Err(_) => return Err(ProgramError::InvalidConstant(index)),
+            }
+        }
+        let debug = match flags & FLAG_DEBUG {
+            0 => None,
+            _ => Some(SourceMap::from_bytes(reader.block("debug")?)?),
+        };
+        if reader.pos != bytes.len() {
+            return Err(ProgramError::TrailingBytes(bytes.len() - reader.pos));
+        }
+        Ok(Program {
+            entry,
+            code,
+            constants,
+            debug,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 定数ひとつとソースマップを持つ小さなプログラム
+    fn program() -> Program {
+        Program {
+            entry: 1,
+            code: vec![0, 1, 2, 3],
+            constants: vec!["abc".to_string()],
+            debug: Some(SourceMap {
+                ranges: vec![SourceRange {
+                    start: 0,
+                    end: 4,
+                    block: 7,
+                }],
+            }),
+        }
+    }
+
+    // ヘッダ12バイトとコードの長さ4バイトのあとにコードが続く
+    const CODE: usize = 16;
+    // コード4バイト、定数の個数4バイト、定数の長さ4バイトのあとに定数が続く
+    const CONSTANT: usize = CODE + 12;
+
+    #[test]
+    fn round_trips_a_program() {
+        let with_debug = program();
+        assert_eq!(Program::from_bytes(&with_debug.to_bytes()), Ok(with_debug));
+        let without_debug = Program {
+            debug: None,
+            ..program()
+        };
+        assert_eq!(
+            Program::from_bytes(&without_debug.to_bytes()),
+            Ok(without_debug)
+        );
+    }
+
+    #[test]
+    fn rejects_a_truncated_header() {
+        let bytes = program().to_bytes();
+        for len in [0, 3, 4, 6, 11] {
+            assert_eq!(
+                Program::from_bytes(&bytes[..len]),
+                Err(ProgramError::Truncated("header"))
+            );
+        }
+        assert_eq!(
+            Program::from_bytes(&bytes[..CODE + 2]),
+            Err(ProgramError::Truncated("code"))
+        );
+        assert_eq!(
+            Program::from_bytes(&bytes[..CONSTANT + 1]),
+            Err(ProgramError::Truncated("constants"))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut bytes = program().to_bytes();
+        bytes[0..4].copy_from_slice(b"NDSQ");
+        assert_eq!(Program::from_bytes(&bytes), Err(ProgramError::BadMagic));
+    }
+
+    #[test]
+    fn rejects_an_unsupported_version() {
+        let mut bytes = program().to_bytes();
+        bytes[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            Program::from_bytes(&bytes),
+            Err(ProgramError::UnsupportedVersion(FORMAT_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_flags() {
+        let mut bytes = program().to_bytes();
+        bytes[6..8].copy_from_slice(&3u16.to_le_bytes());
+        assert_eq!(
+            Program::from_bytes(&bytes),
+            Err(ProgramError::UnknownFlags(3))
+        );
+    }
+
+    #[test]
+    fn rejects_an_entry_past_the_end() {
+        let mut bytes = program().to_bytes();
+        bytes[8..12].copy_from_slice(&4u32.to_le_bytes());
+        assert_eq!(
+            Program::from_bytes(&bytes),
+            Err(ProgramError::EntryOutOfRange(4))
+        );
+    }
+
+    #[test]
+    fn rejects_an_invalid_utf8_constant() {
+        let mut bytes = program().to_bytes();
+        bytes[CONSTANT] = 0xFF;
+        assert_eq!(
+            Program::from_bytes(&bytes),
+            Err(ProgramError::InvalidConstant(0))
+        );
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut bytes = program().to_bytes();
+        bytes.extend([0, 0]);
+        assert_eq!(
+            Program::from_bytes(&bytes),
+            Err(ProgramError::TrailingBytes(2))
+        );
+    }
+
+    #[test]
+    fn rejects_a_broken_source_map() {
+        let mut bytes = program().to_bytes();
+        // 最後の範囲のstartをendより後ろにする
+        let start = bytes.len() - 12;
+        bytes[start..start + 4].copy_from_slice(&5u32.to_le_bytes());
+        assert_eq!(
+            Program::from_bytes(&bytes),
+            Err(ProgramError::InvalidSourceMap)
+        );
+    }
+}
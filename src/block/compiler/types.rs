@@ -0,0 +1,109 @@
+// 値に付ける型　コード生成と型推論の両方で使う
+// 型が分からないもの（まだ代入されていない変数など）はUnknownで、どの型とも合うものとして扱う
+// Varは型推論の型変数で、推論の中で使われ方から決めていく
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Integer,
+    Float,
+    String,
+    Bool,
+    Function(Vec<Type>, Box<Type>),
+    Array(Box<Type>),
+    Unknown,
+    Var(u32),
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Type::Integer => write!(f, "integer"),
+            Type::Float => write!(f, "float"),
+            Type::String => write!(f, "string"),
+            Type::Bool => write!(f, "boolean"),
+            Type::Function(params, ret) => {
+                let params: Vec<String> = params.iter().map(|param| param.to_string()).collect();
+                write!(f, "({}) -> {}", params.join(", "), ret)
+            }
+            Type::Array(element) => write!(f, "array<{}>", element),
+            Type::Unknown => write!(f, "unknown"),
+            // 'a, 'b, ..., 'z, 'a1, ...
+            Type::Var(n) => match n / 26 {
+                0 => write!(f, "'{}", (b'a' + (n % 26) as u8) as char),
+                k => write!(f, "'{}{}", (b'a' + (n % 26) as u8) as char, k),
+            },
+        }
+    }
+}
+
+impl Type {
+    // 表示と同じ書き方の型を読む　型注釈に使う
+    // integer, array<float>, (integer, integer) -> integer など
+    pub fn parse(text: &str) -> Option<Type> {
+        let text = text.trim();
+        match text {
+            "integer" => return Some(Type::Integer),
+            "float" => return Some(Type::Float),
+            "string" => return Some(Type::String),
+            "boolean" => return Some(Type::Bool),
+            _ => {}
+        }
+        if let Some(element) = text
+            .strip_prefix("array<")
+            .and_then(|text| text.strip_suffix('>'))
+        {
+            return Type::parse(element).map(|element| Type::Array(Box::new(element)));
+        }
+        // (引数, ...) -> 戻り値
+        let close = match_paren(text.strip_prefix('(')?)? + 1;
+        let ret = Type::parse(text[close + 1..].trim_start().strip_prefix("->")?)?;
+        let inner = &text[1..close];
+        let params = if inner.trim().is_empty() {
+            vec![]
+        } else {
+            split_params(inner)
+                .into_iter()
+                .map(Type::parse)
+                .collect::<Option<Vec<Type>>>()?
+        };
+        Some(Type::Function(params, Box::new(ret)))
+    }
+}
+
+// 括弧と<>の深さ　->の>は数えない
+fn depth_change(text: &str, i: usize) -> i32 {
+    match text.as_bytes()[i] {
+        b'(' | b'<' => 1,
+        b')' => -1,
+        b'>' if i == 0 || text.as_bytes()[i - 1] != b'-' => -1,
+        _ => 0,
+    }
+}
+
+// 開き括弧の後のテキストで、対応する閉じ括弧の位置
+fn match_paren(text: &str) -> Option<usize> {
+    let mut depth = 1;
+    for i in 0..text.len() {
+        depth += depth_change(text, i);
+        if depth == 0 {
+            return Some(i);
+        }
+    }
+    None
+}
+
+// 一番外側のカンマで分ける
+fn split_params(text: &str) -> Vec<&str> {
+    let mut params: Vec<&str> = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for i in 0..text.len() {
+        depth += depth_change(text, i);
+        if depth == 0 && text.as_bytes()[i] == b',' {
+            params.push(&text[start..i]);
+            start = i + 1;
+        }
+    }
+    params.push(&text[start..]);
+    params
+}
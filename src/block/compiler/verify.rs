@@ -0,0 +1,384 @@
+// 実行する前にバイトコードを静的に検査する
+// 全命令をデコードできるか、定数で積まれたジャンプ先が命令の先頭か、
+// 入口から静的に辿れる範囲でスタックの深さが合っているかを調べる
+// スタック上のアドレスへ飛ぶジャンプの先と、ExportFPの後は深さが分からないので調べない
+// ただし関数呼び出しの戻り先（PushFPの直後に積むアドレス）へはPushFPの後の深さで戻ってくるとみなす
+
+use super::program::Program;
+use super::{Opecodes, STACK_SIZE};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyError {
+    InvalidOpcode { offset: usize, byte: u8 },
+    TruncatedOperand(usize),
+    EntryNotOnInstruction(u32),
+    InvalidJumpTarget { offset: usize, target: i64 },
+    InvalidConstant { offset: usize, index: u64 },
+    MisalignedFrameOffset { offset: usize, value: i64 },
+    FrameOffsetOutOfRange { offset: usize, value: i64 },
+    StackUnderflow(usize),
+    StackOverflow(usize),
+    StackDepthMismatch { offset: usize, depths: (i64, i64) },
+    MissingEnd,
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            VerifyError::InvalidOpcode { offset, byte } => {
+                write!(f, "invalid opcode {:#X} at {}.", byte, offset)
+            }
+            VerifyError::TruncatedOperand(offset) => {
+                write!(f, "operand of the instruction at {} is truncated.", offset)
+            }
+            VerifyError::EntryNotOnInstruction(entry) => {
+                write!(
+                    f,
+                    "entry point {} is not the start of an instruction.",
+                    entry
+                )
+            }
+            VerifyError::InvalidJumpTarget { offset, target } => write!(
+                f,
+                "jump at {} goes to {}, which is not the start of an instruction.",
+                offset, target
+            ),
+            VerifyError::InvalidConstant { offset, index } => {
+                write!(f, "invalid constant index {} at {}.", index, offset)
+            }
+            VerifyError::MisalignedFrameOffset { offset, value } => write!(
+                f,
+                "frame offset {} at {} is not a multiple of 8.",
+                value, offset
+            ),
+            VerifyError::FrameOffsetOutOfRange { offset, value } => write!(
+                f,
+                "frame offset {} at {} is outside of the stack.",
+                value, offset
+            ),
+            VerifyError::StackUnderflow(offset) => write!(f, "stack underflow at {}.", offset),
+            VerifyError::StackOverflow(offset) => write!(f, "stack overflow at {}.", offset),
+            VerifyError::StackDepthMismatch { offset, depths } => write!(
+                f,
+                "stack depth at {} is {} on one path and {} on another.",
+                offset, depths.0, depths.1
+            ),
+            VerifyError::MissingEnd => write!(f, "the program has no 'end' instruction."),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+struct Instruction {
+    offset: usize,
+    opcode: Opecodes,
+    operand: i64,
+}
+
+// 取り出す値の数と積む値の数（8バイト単位）
+fn stack_effect(opcode: Opecodes) -> (i64, i64) {
+    match opcode {
+        Opecodes::CopySP | Opecodes::PushS64 | Opecodes::PushRET | Opecodes::PushFP => (0, 1),
+        Opecodes::PushStr => (0, 1),
+        Opecodes::OverWriteSP | Opecodes::OutputI | Opecodes::OutputF | Opecodes::OutputS => (1, 1),
+        Opecodes::Not | Opecodes::Length => (1, 1),
+        Opecodes::PopS64 | Opecodes::SetRET | Opecodes::ResetFP | Opecodes::Jump => (1, 0),
+        Opecodes::IfNotJump => (2, 0),
+        Opecodes::SubstrS | Opecodes::SetA => (3, 1),
+        Opecodes::SetFP | Opecodes::ExportFP | Opecodes::End => (0, 0),
+        // 未実装なので現れない
+        Opecodes::PushS32 | Opecodes::PopS32 | Opecodes::SaveR => (0, 0),
+        // 残りは二項演算
+        _ => (2, 1),
+    }
+}
+
+fn decode(code: &[u8]) -> Result<Vec<Instruction>, VerifyError> {
+    let mut instructions: Vec<Instruction> = vec![];
+    let mut offset = 0;
+    while offset < code.len() {
+        let byte = code[offset];
+        let opcode = match Opecodes::try_from(byte) {
+            Ok(Opecodes::PushS32 | Opecodes::PopS32 | Opecodes::SaveR) | Err(_) => {
+                return Err(VerifyError::InvalidOpcode { offset, byte })
+            }
+            Ok(opcode) => opcode,
+        };
+        let size = opcode.operand_size();
+        let operand = match size {
+            0 => 0,
+            _ => match code.get(offset + 1..offset + 1 + size) {
+                Some(bytes) => i64::from_le_bytes(bytes.try_into().unwrap_or_default()),
+                None => return Err(VerifyError::TruncatedOperand(offset)),
+            },
+        };
+        instructions.push(Instruction {
+            offset,
+            opcode,
+            operand,
+        });
+        offset += 1 + size;
+    }
+    Ok(instructions)
+}
+
+// k番目がジャンプで、直前のPushS64で飛び先が決まっていればその位置
+fn static_target(instructions: &[Instruction], k: usize) -> Option<i64> {
+    match (
+        instructions[k].opcode,
+        k.checked_sub(1).map(|j| &instructions[j]),
+    ) {
+        (Opecodes::Jump | Opecodes::IfNotJump, Some(prev)) if prev.opcode == Opecodes::PushS64 => {
+            Some(prev.operand)
+        }
+        _ => None,
+    }
+}
+
+// k番目がPushFPの直後のPushS64なら関数からの戻り先
+fn return_target(instructions: &[Instruction], k: usize) -> Option<i64> {
+    match (
+        instructions[k].opcode,
+        k.checked_sub(1).map(|j| instructions[j].opcode),
+    ) {
+        (Opecodes::PushS64, Some(Opecodes::PushFP)) => Some(instructions[k].operand),
+        _ => None,
+    }
+}
+
+pub fn verify(program: &Program) -> Result<(), VerifyError> {
+    let instructions = decode(&program.code)?;
+    let index_of = |offset: i64| {
+        instructions
+            .binary_search_by_key(&offset, |instruction| instruction.offset as i64)
+            .ok()
+    };
+
+    if !instructions
+        .iter()
+        .any(|instruction| instruction.opcode == Opecodes::End)
+    {
+        return Err(VerifyError::MissingEnd);
+    }
+    for (k, instruction) in instructions.iter().enumerate() {
+        match instruction.opcode {
+            Opecodes::PushStr if instruction.operand as u64 >= program.constants.len() as u64 => {
+                return Err(VerifyError::InvalidConstant {
+                    offset: instruction.offset,
+                    index: instruction.operand as u64,
+                })
+            }
+            Opecodes::CopySP | Opecodes::OverWriteSP if instruction.operand % 8 != 0 => {
+                return Err(VerifyError::MisalignedFrameOffset {
+                    offset: instruction.offset,
+                    value: instruction.operand,
+                })
+            }
+            // FPはスタックの中を指すので、スタックより大きく離れた位置は読めない
+            Opecodes::CopySP | Opecodes::OverWriteSP
+                if instruction.operand.unsigned_abs() >= STACK_SIZE as u64 =>
+            {
+                return Err(VerifyError::FrameOffsetOutOfRange {
+                    offset: instruction.offset,
+                    value: instruction.operand,
+                })
+            }
+            _ => {}
+        }
+        if let Some(target) = static_target(&instructions, k).or(return_target(&instructions, k)) {
+            if index_of(target).is_none() {
+                return Err(VerifyError::InvalidJumpTarget {
+                    offset: instruction.offset,
+                    target,
+                });
+            }
+        }
+    }
+
+    // 入口から辿れる命令のスタックの深さを求める
+    let Some(entry) = index_of(program.entry as i64) else {
+        return Err(VerifyError::EntryNotOnInstruction(program.entry));
+    };
+    let mut depths: Vec<Option<i64>> = vec![None; instructions.len()];
+    let mut work: Vec<(usize, i64)> = vec![(entry, 0)];
+    while let Some((k, depth)) = work.pop() {
+        let instruction = &instructions[k];
+        match depths[k] {
+            Some(known) if known == depth => continue,
+            Some(known) => {
+                return Err(VerifyError::StackDepthMismatch {
+                    offset: instruction.offset,
+                    depths: (known, depth),
+                })
+            }
+            None => depths[k] = Some(depth),
+        }
+        let (pops, pushes) = stack_effect(instruction.opcode);
+        if depth < pops {
+            return Err(VerifyError::StackUnderflow(instruction.offset));
+        }
+        let next = depth - pops + pushes;
+        if next * 8 > STACK_SIZE as i64 {
+            return Err(VerifyError::StackOverflow(instruction.offset));
+        }
+        if let Some(target) = return_target(&instructions, k).and_then(index_of) {
+            work.push((target, depth));
+        }
+        let target = static_target(&instructions, k).and_then(index_of);
+        match instruction.opcode {
+            Opecodes::End | Opecodes::ExportFP => {}
+            Opecodes::Jump => work.extend(target.map(|target| (target, next))),
+            Opecodes::IfNotJump => {
+                work.extend(target.map(|target| (target, next)));
+                work.extend((k + 1 < instructions.len()).then_some((k + 1, next)));
+            }
+            _ => work.extend((k + 1 < instructions.len()).then_some((k + 1, next))),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::asm::assemble;
+    use super::*;
+    use crate::block::compiler::Environment;
+    use crate::block::sexpr;
+
+    fn program(text: &str) -> Program {
+        let (code, constants) = assemble(text).unwrap();
+        Program {
+            entry: 0,
+            code,
+            constants,
+            debug: None,
+        }
+    }
+
+    fn compiled(source: &str) -> Program {
+        let node = sexpr::read(source).unwrap();
+        node.compile_program(&mut Environment::default()).unwrap().0
+    }
+
+    #[test]
+    fn accepts_compiled_programs() {
+        let sources = [
+            "(list (identifier_list a b) (set a 3) (set b (addi a 5)) (printi (muli a b)))",
+            "(list (identifier_list i) (set i 0) (while (lti i 3) (set i (addi i 1))) (printi i))",
+            r#"(list (identifier_list s) (set s (concat "a" "b")) (prints s))"#,
+            "(list (identifier_list f) (set f (lambda (identifier_list n) (muli n 2))) (printi (f 21)))",
+        ];
+        for source in sources {
+            assert_eq!(verify(&compiled(source)), Ok(()), "{}", source);
+        }
+    }
+
+    #[test]
+    fn accepts_a_forward_branch() {
+        let text = "push.s64 1\npush.s64 skip\nifnotjump\npush.s64 2\npop.s64\nskip:\nend";
+        assert_eq!(verify(&program(text)), Ok(()));
+    }
+
+    #[test]
+    fn rejects_a_program_without_end() {
+        assert_eq!(
+            verify(&program("push.s64 1\npop.s64")),
+            Err(VerifyError::MissingEnd)
+        );
+    }
+
+    #[test]
+    fn rejects_unimplemented_opcodes() {
+        let byte = Opecodes::PushS32 as u8;
+        assert_eq!(
+            verify(&program(&format!("end\n.byte {}", byte))),
+            Err(VerifyError::InvalidOpcode { offset: 1, byte })
+        );
+    }
+
+    #[test]
+    fn rejects_a_truncated_operand() {
+        let text = format!("end\n.byte {}\n.byte 1", Opecodes::PushS64 as u8);
+        assert_eq!(
+            verify(&program(&text)),
+            Err(VerifyError::TruncatedOperand(1))
+        );
+    }
+
+    #[test]
+    fn rejects_a_jump_into_an_operand() {
+        assert_eq!(
+            verify(&program("push.s64 3\njump\nend")),
+            Err(VerifyError::InvalidJumpTarget {
+                offset: 9,
+                target: 3
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_a_missing_constant() {
+        assert_eq!(
+            verify(&program("push.str 0\noutput.s\nend")),
+            Err(VerifyError::InvalidConstant {
+                offset: 0,
+                index: 0
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_a_misaligned_frame_offset() {
+        assert_eq!(
+            verify(&program("copysp 4\nend")),
+            Err(VerifyError::MisalignedFrameOffset {
+                offset: 0,
+                value: 4
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_a_frame_offset_outside_of_the_stack() {
+        let value = i64::MAX - 7;
+        assert_eq!(
+            verify(&program(&format!("copysp {}\nend", value))),
+            Err(VerifyError::FrameOffsetOutOfRange { offset: 0, value })
+        );
+    }
+
+    #[test]
+    fn rejects_an_entry_inside_an_instruction() {
+        let mut program = program("push.s64 1\npop.s64\nend");
+        program.entry = 1;
+        assert_eq!(verify(&program), Err(VerifyError::EntryNotOnInstruction(1)));
+    }
+
+    #[test]
+    fn rejects_stack_underflow() {
+        assert_eq!(
+            verify(&program("push.s64 1\nadd.i\nend")),
+            Err(VerifyError::StackUnderflow(9))
+        );
+    }
+
+    #[test]
+    fn rejects_stack_overflow() {
+        let count = STACK_SIZE / 8 + 1;
+        let text = "push.s64 1\n".repeat(count) + "end";
+        assert_eq!(
+            verify(&program(&text)),
+            Err(VerifyError::StackOverflow((count - 1) * 9))
+        );
+    }
+
+    #[test]
+    fn rejects_branches_with_different_depths() {
+        let text = "push.s64 1\npush.s64 join\nifnotjump\npush.s64 2\njoin:\nend";
+        assert!(matches!(
+            verify(&program(text)),
+            Err(VerifyError::StackDepthMismatch { offset: 28, .. })
+        ));
+    }
+}
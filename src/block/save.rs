@@ -0,0 +1,201 @@
+// ノードグラフの保存と読み込み　RON形式でブロックのidと接続をそのまま書き出す
+
+use super::*;
+use serde::{Deserialize, Serialize};
+
+pub const SAVE_FILE_VERSION: u32 = 1;
+pub const DEFAULT_SAVE_PATH: &str = "graph.ron";
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedBlock {
+    pub id: u32,
+    pub text: String,
+    pub block_type: BlockType,
+    pub position: [f32; 2],
+    pub inputs: Vec<u32>,
+    pub comment: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SaveFile {
+    pub version: u32,
+    pub start_block: u32,
+    pub blocks: Vec<SavedBlock>,
+}
+
+impl SaveFile {
+    pub fn new(block_list: &BlockList, start_block: &StartBlock) -> Self {
+        let mut blocks: Vec<SavedBlock> = block_list
+            .item
+            .iter()
+            .map(|(id, (_, block))| SavedBlock {
+                id: *id,
+                text: block.data.text.clone(),
+                block_type: block.data.block_type,
+                position: [block.position.x, block.position.y],
+                inputs: block.inputs.clone(),
+                comment: block.comment.clone(),
+            })
+            .collect();
+        blocks.sort_by_key(|block| block.id); // 保存するたびに順番が変わらないように
+        SaveFile {
+            version: SAVE_FILE_VERSION,
+            start_block: start_block.start_block,
+            blocks,
+        }
+    }
+
+    pub fn to_ron(&self) -> Result<String, String> {
+        ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(|err| err.to_string())
+    }
+
+    pub fn from_ron(text: &str) -> Result<Self, String> {
+        let file: SaveFile = ron::from_str(text).map_err(|err| err.to_string())?;
+        if file.version != SAVE_FILE_VERSION {
+            return Err(format!("unsupported file version {}.", file.version));
+        }
+        let ids: Vec<u32> = file.blocks.iter().map(|block| block.id).collect();
+        if !ids.contains(&file.start_block) {
+            return Err(format!("start block {} does not exist.", file.start_block));
+        }
+        for block in file.blocks.iter() {
+            if block.id == 0 || ids.iter().filter(|id| **id == block.id).count() > 1 {
+                return Err(format!("invalid block id {}.", block.id));
+            }
+            if let Some(input) = block.inputs.iter().find(|input| !ids.contains(input)) {
+                return Err(format!(
+                    "block {} refers to missing block {}.",
+                    block.id, input
+                ));
+            }
+        }
+        // 入力が循環しているとパースが終わらない
+        let block_list = file.to_block_list();
+        if let Some(block) = file.blocks.iter().find(|block| {
+            block
+                .inputs
+                .iter()
+                .any(|input| block_list.reaches(*input, block.id))
+        }) {
+            return Err(format!("block {} is connected to its own input.", block.id));
+        }
+        Ok(file)
+    }
+
+    // 画面に出さずに使うとき用　Entityはダミー
+    pub fn to_block_list(&self) -> BlockList {
+        let mut block_list = BlockList::default();
+        for saved in self.blocks.iter() {
+            block_list
+                .item
+                .insert(saved.id, (Entity::PLACEHOLDER, saved.to_block()));
+        }
+        block_list
+    }
+}
+
+impl SavedBlock {
+    pub fn to_block(&self) -> Block {
+        Block {
+            data: BlockData {
+                text: self.text.clone(),
+                block_type: self.block_type,
+            },
+            position: Vec2::new(self.position[0], self.position[1]),
+            inputs: self.inputs.clone(),
+            comment: self.comment.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn saved(id: u32, text: &str, block_type: BlockType, inputs: &[u32]) -> SavedBlock {
+        SavedBlock {
+            id,
+            text: text.to_string(),
+            block_type,
+            position: [0.0, 0.0],
+            inputs: inputs.to_vec(),
+            comment: "".to_string(),
+        }
+    }
+
+    // (printi (addi 1 2))
+    fn file() -> SaveFile {
+        SaveFile {
+            version: SAVE_FILE_VERSION,
+            start_block: 1,
+            blocks: vec![
+                saved(1, "printi", BlockType::Identifier, &[2]),
+                saved(2, "addi", BlockType::Identifier, &[3, 4]),
+                saved(3, "1", BlockType::Value, &[]),
+                saved(4, "2", BlockType::Value, &[]),
+            ],
+        }
+    }
+
+    fn load(file: &SaveFile) -> Result<SaveFile, String> {
+        SaveFile::from_ron(&file.to_ron().unwrap())
+    }
+
+    fn load_error(file: &SaveFile) -> String {
+        match load(file) {
+            Ok(_) => panic!("the file was loaded."),
+            Err(msg) => msg,
+        }
+    }
+
+    #[test]
+    fn loads_a_saved_graph() {
+        let loaded = load(&file()).unwrap();
+        assert_eq!(loaded.start_block, 1);
+        assert_eq!(loaded.blocks.len(), 4);
+        assert!(loaded.to_block_list().parse(1).is_ok());
+    }
+
+    #[test]
+    fn rejects_another_version() {
+        let mut file = file();
+        file.version = SAVE_FILE_VERSION + 1;
+        assert!(load_error(&file).contains("version"));
+    }
+
+    #[test]
+    fn rejects_a_missing_start_block() {
+        let mut file = file();
+        file.start_block = 9;
+        assert!(load_error(&file).contains("start block 9"));
+    }
+
+    #[test]
+    fn rejects_duplicate_ids() {
+        let mut file = file();
+        file.blocks.push(saved(3, "5", BlockType::Value, &[]));
+        assert_eq!(load_error(&file), "invalid block id 3.");
+    }
+
+    #[test]
+    fn rejects_a_dangling_input() {
+        let mut file = file();
+        file.blocks[1].inputs.push(9);
+        assert_eq!(load_error(&file), "block 2 refers to missing block 9.");
+    }
+
+    #[test]
+    fn rejects_a_cycle() {
+        let mut file = file();
+        file.blocks[1].inputs[1] = 1;
+        assert!(load_error(&file).contains("its own input"));
+    }
+
+    #[test]
+    fn rejects_a_block_connected_to_itself() {
+        let mut file = file();
+        file.blocks[2].inputs.push(3);
+        assert_eq!(load_error(&file), "block 3 is connected to its own input.");
+    }
+}
@@ -0,0 +1,384 @@
+// S式のテキストとAstNodeの相互変換
+// (list (identifier_list a b) (set a 3) (printi (muli a b)))
+// 括弧の先頭がset、lambda、if、whileなら文、list、identifier_listならリスト、それ以外は関数呼び出し
+// 文やリストと同じ名前の関数（配列のsetなど）を呼ぶときは (call set a 0 5) のようにcallを前に付ける
+// 関数呼び出しには引数が一つ以上いる
+
+use super::*;
+
+const STATEMENTS: [&str; 4] = ["set", "lambda", "if", "while"];
+const LISTS: [&str; 2] = ["list", "identifier_list"];
+const CALL: &str = "call";
+const LINE_WIDTH: usize = 80;
+
+enum Token {
+    Open,
+    Close,
+    Str(String),
+    Atom(String),
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens: Vec<Token> = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            ';' => {
+                // 行末までコメント
+                while chars.next_if(|c| *c != '\n').is_some() {}
+            }
+            '"' => {
+                chars.next();
+                let mut str = "".to_string();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => str.push('\n'),
+                            Some('t') => str.push('\t'),
+                            Some(c @ ('"' | '\\')) => str.push(c),
+                            Some(c) => return Err(format!("unknown escape '\\{}'.", c)),
+                            None => return Err("unterminated string.".to_string()),
+                        },
+                        Some(c) => str.push(c),
+                        None => return Err("unterminated string.".to_string()),
+                    }
+                }
+                tokens.push(Token::Str(str));
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            _ => {
+                let mut atom = "".to_string();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !"()\";".contains(*c)) {
+                    atom.push(c);
+                }
+                tokens.push(Token::Atom(atom));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn read_atom(atom: &str) -> AstNode {
+    match atom.parse::<i64>() {
+        Ok(num) => AstNode::ValueInteger(num),
+        Err(_) => match atom.parse::<f64>() {
+            Ok(num) => AstNode::ValueFloat(num),
+            Err(_) => match atom.parse::<bool>() {
+                Ok(b) => AstNode::ValueBool(b),
+                Err(_) => AstNode::Identifier(atom.to_string()),
+            },
+        },
+    }
+}
+
+fn read_expression(tokens: &[Token], pos: &mut usize) -> Result<AstNode, String> {
+    match tokens.get(*pos) {
+        None => Err("unexpected end of input.".to_string()),
+        Some(Token::Close) => Err("unexpected ')'.".to_string()),
+        Some(Token::Str(str)) => {
+            *pos += 1;
+            Ok(AstNode::ValueStr(str.clone()))
+        }
+        Some(Token::Atom(atom)) => {
+            *pos += 1;
+            Ok(read_atom(atom))
+        }
+        Some(Token::Open) => {
+            *pos += 1;
+            let head = match tokens.get(*pos) {
+                Some(Token::Atom(atom)) if !matches!(read_atom(atom), AstNode::Identifier(_)) => {
+                    return Err(format!("expected a name after '(' but found '{}'.", atom))
+                }
+                Some(Token::Atom(atom)) => atom.clone(),
+                Some(Token::Close) => return Err("empty list '()'.".to_string()),
+                Some(_) => return Err("expected a name after '('.".to_string()),
+                None => return Err("unexpected end of input.".to_string()),
+            };
+            *pos += 1;
+            let mut args: Vec<AstNode> = vec![];
+            loop {
+                match tokens.get(*pos) {
+                    Some(Token::Close) => {
+                        *pos += 1;
+                        break;
+                    }
+                    Some(_) => args.push(read_expression(tokens, pos)?),
+                    None => return Err(format!("missing ')' after '({}'.", head)),
+                }
+            }
+            if head == CALL {
+                match args.split_first() {
+                    Some((AstNode::Identifier(func), args)) if !args.is_empty() => {
+                        Ok(AstNode::Function {
+                            func: func.clone(),
+                            args: args.to_vec(),
+                        })
+                    }
+                    Some((AstNode::Identifier(func), _)) => {
+                        Err(format!("'({})' needs at least one argument.", func))
+                    }
+                    _ => Err(format!("expected a function name after '({}'.", CALL)),
+                }
+            } else if STATEMENTS.contains(&head.as_str()) {
+                Ok(AstNode::Statement {
+                    statement: head,
+                    options: args,
+                })
+            } else if LISTS.contains(&head.as_str()) {
+                Ok(AstNode::List {
+                    name: head,
+                    codes: args,
+                })
+            } else if args.is_empty() {
+                // 引数のない呼び出しはブロックにすると変数と区別できない
+                Err(format!("'({})' needs at least one argument.", head))
+            } else {
+                Ok(AstNode::Function { func: head, args })
+            }
+        }
+    }
+}
+
+// テキスト全体で一つの式を読む
+pub fn read(text: &str) -> Result<AstNode, ParseError> {
+    let tokens = tokenize(text).map_err(ParseError::Syntax)?;
+    let mut pos = 0;
+    let node = read_expression(&tokens, &mut pos).map_err(ParseError::Syntax)?;
+    if pos != tokens.len() {
+        return Err(ParseError::Syntax(
+            "unexpected input after the expression.".to_string(),
+        ));
+    }
+    Ok(node)
+}
+
+fn print_str(str: &str) -> String {
+    let mut res = "\"".to_string();
+    for c in str.chars() {
+        match c {
+            '"' => res += "\\\"",
+            '\\' => res += "\\\\",
+            '\n' => res += "\\n",
+            '\t' => res += "\\t",
+            _ => res.push(c),
+        }
+    }
+    res + "\""
+}
+
+// 括弧で囲んで書くノードの先頭の名前と引数
+// 文やリストと読まれてしまう名前の関数呼び出しはcallを付ける
+fn head_and_args(node: &AstNode) -> Option<(String, &Vec<AstNode>)> {
+    match node.inner() {
+        AstNode::Statement { statement, options } => Some((statement.clone(), options)),
+        AstNode::Function { func, args }
+            if STATEMENTS.contains(&func.as_str())
+                || LISTS.contains(&func.as_str())
+                || func == CALL =>
+        {
+            Some((format!("{} {}", CALL, func), args))
+        }
+        AstNode::Function { func, args } => Some((func.clone(), args)),
+        AstNode::List { name, codes } => Some((name.clone(), codes)),
+        _ => None,
+    }
+}
+
+fn print_flat(node: &AstNode) -> String {
+    match node.inner() {
+        AstNode::ValueInteger(num) => num.to_string(),
+        AstNode::ValueFloat(num) => format!("{:?}", num), // 3.0が整数として読まれないように
+        AstNode::ValueStr(str) => print_str(str),
+        AstNode::ValueBool(b) => b.to_string(),
+        AstNode::Identifier(name) => name.clone(),
+        _ => {
+            let (head, args) = head_and_args(node).unwrap();
+            let mut res = format!("({}", head);
+            for arg in args {
+                res += " ";
+                res += &print_flat(arg);
+            }
+            res + ")"
+        }
+    }
+}
+
+fn print_indented(node: &AstNode, indent: usize, res: &mut String) {
+    let flat = print_flat(node);
+    match head_and_args(node) {
+        // 一行に収まらないときは引数を一つずつ改行して字下げする
+        Some((head, args)) if indent + flat.len() > LINE_WIDTH => {
+            *res += &format!("({}", head);
+            for arg in args {
+                *res += &format!("\n{}", " ".repeat(indent + 2));
+                print_indented(arg, indent + 2, res);
+            }
+            *res += ")";
+        }
+        _ => *res += &flat,
+    }
+}
+
+pub fn print(node: &AstNode) -> String {
+    let mut res = "".to_string();
+    print_indented(node, 0, &mut res);
+    res
+}
+
+// スタートのブロックから辿れるグラフをS式にする
+pub fn print_blocks(
+    block_list: &BlockList,
+    start_block: &StartBlock,
+) -> Result<String, ParseError> {
+    block_list
+        .parse(start_block.start_block)
+        .map(|node| print(&node))
+}
+
+// 貼り付けたコードをブロックにするときの、ノード一つ分のブロックと入力になる子ノード
+pub fn to_block_data(node: &AstNode) -> (BlockData, &[AstNode]) {
+    let (text, block_type) = match node.inner() {
+        AstNode::Statement { statement, .. } => (statement.clone(), BlockType::Statement),
+        AstNode::List { name, .. } => (name.clone(), BlockType::List),
+        AstNode::Function { func, .. } => (func.clone(), BlockType::Identifier),
+        AstNode::Identifier(name) => (name.clone(), BlockType::Identifier),
+        AstNode::ValueInteger(num) => (num.to_string(), BlockType::Value),
+        AstNode::ValueFloat(num) => (format!("{:?}", num), BlockType::Value),
+        AstNode::ValueStr(str) => (format!("\"{}\"", str), BlockType::Value),
+        AstNode::ValueBool(b) => (b.to_string(), BlockType::Value),
+        AstNode::Located { .. } => unreachable!(),
+    };
+    let children = match head_and_args(node) {
+        Some((_, args)) => args.as_slice(),
+        None => &[],
+    };
+    (BlockData { text, block_type }, children)
+}
+
+const BLOCK_GAP: f32 = 20.0;
+const LEVEL_HEIGHT: f32 = 80.0;
+
+// 木として並べたときにノードとその子孫が占める横幅
+pub fn subtree_width(node: &AstNode) -> f32 {
+    let (data, children) = to_block_data(node);
+    let own = data.text.len() as f32 * 15.0; // spawn_blockのブロックの幅
+    let children_width: f32 = children.iter().map(subtree_width).sum::<f32>()
+        + BLOCK_GAP * children.len().saturating_sub(1) as f32;
+    own.max(children_width)
+}
+
+// 子ノードを左から並べたときのそれぞれの中心の位置
+pub fn children_positions(node: &AstNode, center: Vec2) -> Vec<Vec2> {
+    let (_, children) = to_block_data(node);
+    let widths: Vec<f32> = children.iter().map(subtree_width).collect();
+    let total = widths.iter().sum::<f32>() + BLOCK_GAP * widths.len().saturating_sub(1) as f32;
+    let mut left = center.x - total / 2.0;
+    widths
+        .iter()
+        .map(|width| {
+            let position = Vec2::new(left + width / 2.0, center.y - LEVEL_HEIGHT);
+            left += width + BLOCK_GAP;
+            position
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 貼り付けと同じようにブロックにして、グラフからまた読む
+    fn add_blocks(node: &AstNode, block_list: &mut BlockList) -> u32 {
+        let (data, children) = to_block_data(node);
+        let inputs: Vec<u32> = children
+            .iter()
+            .map(|child| add_blocks(child, block_list))
+            .collect();
+        let id = block_list.item.len() as u32 + 1;
+        let block = Block {
+            data,
+            position: Vec2::ZERO,
+            inputs,
+            comment: "".to_string(),
+        };
+        block_list.item.insert(id, (Entity::PLACEHOLDER, block));
+        id
+    }
+
+    fn through_blocks(node: &AstNode) -> AstNode {
+        let mut block_list = BlockList::default();
+        let id = add_blocks(node, &mut block_list);
+        block_list.parse(id).unwrap()
+    }
+
+    fn assert_round_trip(source: &str) {
+        let node = read(source).unwrap();
+        assert_eq!(print(&node), source);
+        assert_eq!(print(&read(&print(&node)).unwrap()), source);
+        assert_eq!(print(&through_blocks(&node)), source);
+    }
+
+    #[test]
+    fn round_trips_programs() {
+        assert_round_trip(
+            "(list (identifier_list a b) (set a 3) (set b (addi a 5)) (printi (muli a b)))",
+        );
+        assert_round_trip("(printf (addf 3.0 -0.5))");
+        assert_round_trip(r#"(prints (concat "a \"b\"" "\n\t\\"))"#);
+        assert_round_trip("(if (not false) (printi 1) (printi 0))");
+    }
+
+    #[test]
+    fn round_trips_calls_named_like_statements() {
+        assert_round_trip(
+            "(list (identifier_list a) (set a (make-array 3 0)) (call set a 0 5) (get a 0))",
+        );
+        assert_round_trip("(call list 1)");
+        assert_round_trip("(call call 1 2)");
+    }
+
+    #[test]
+    fn keeps_a_set_statement_with_three_options() {
+        let node = AstNode::Statement {
+            statement: "set".to_string(),
+            options: vec![
+                AstNode::Identifier("a".to_string()),
+                AstNode::ValueInteger(1),
+                AstNode::ValueInteger(2),
+            ],
+        };
+        assert_eq!(print(&node), "(set a 1 2)");
+        assert!(matches!(
+            read(&print(&node)).unwrap(),
+            AstNode::Statement { options, .. } if options.len() == 3
+        ));
+    }
+
+    #[test]
+    fn round_trips_long_programs_over_lines() {
+        let source = "(list (identifier_list f) (set f (lambda (identifier_list n g) (if (eqi n 0) 0 (if (eqi n 1) 1 (addi (g (subi n 2) g) (g (subi n 1) g)))))) (printi (f 10 f)))";
+        let printed = print(&read(source).unwrap());
+        assert!(printed.contains('\n'));
+        assert_eq!(print_flat(&read(&printed).unwrap()), source);
+        assert_eq!(print(&through_blocks(&read(&printed).unwrap())), printed);
+    }
+
+    #[test]
+    fn rejects_calls_without_arguments() {
+        // ブロックにすると変数と同じになるので読まない
+        assert!(read("(f)").is_err());
+        assert!(read("(call set)").is_err());
+        assert!(read("(call 1 2)").is_err());
+    }
+}
@@ -0,0 +1,146 @@
+// ウィンドウを開かずに保存したグラフかS式のソースをコンパイル・実行する
+// nodisp run <file>
+// nodisp build <file> [-o <out.bin>]
+// nodisp print <graph.ron>
+// nodisp disasm <file>
+// nodisp verify <file>
+// .ronはエディタで保存したグラフ、.asmはアセンブリ、.binはbuildで書き出したバイトコード、
+// それ以外はS式のソースとして読む
+
+use crate::block::compiler::asm::assemble;
+use crate::block::compiler::disasm::disassemble;
+use crate::block::compiler::program::Program;
+use crate::block::compiler::verify::verify;
+use crate::block::compiler::{execute_vm, AstNode, Environment, Error, VmOptions};
+use crate::block::save::SaveFile;
+use crate::block::{sexpr, StartBlock};
+
+const USAGE: &str = "usage: nodisp run <file>\n       nodisp build <file> [-o <out.bin>]\n       nodisp print <graph.ron>\n       nodisp disasm <file>\n       nodisp verify <file>";
+
+pub fn run(args: &[String]) -> i32 {
+    match args {
+        [command, path] if command == "run" => match load_verified_program(path) {
+            Ok(program) => {
+                match execute_vm(&program.to_bytes(), &VmOptions::default(), &mut |line| {
+                    println!("{}", line)
+                }) {
+                    Ok(()) => 0,
+                    Err(err) => {
+                        eprintln!("{}", Error::Runtime(err));
+                        1
+                    }
+                }
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                1
+            }
+        },
+        [command, path] if command == "print" => match print_graph(path) {
+            Ok(text) => {
+                println!("{}", text);
+                0
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                1
+            }
+        },
+        [command, path] if command == "disasm" => match load_program(path) {
+            Ok(program) => {
+                print!("{}", disassemble(&program.code, &program.constants));
+                0
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                1
+            }
+        },
+        [command, path] if command == "verify" => match load_verified_program(path) {
+            Ok(_) => 0,
+            Err(err) => {
+                eprintln!("{}", err);
+                1
+            }
+        },
+        [command, path, rest @ ..] if command == "build" => {
+            let out = match rest {
+                [] => "out.bin".to_string(),
+                [flag, out] if flag == "-o" => out.clone(),
+                _ => {
+                    eprintln!("{}", USAGE);
+                    return 2;
+                }
+            };
+            match load_verified_program(path) {
+                Ok(program) => match std::fs::write(&out, program.to_bytes()) {
+                    Ok(()) => 0,
+                    Err(err) => {
+                        eprintln!("{}", Error::Write(err.to_string()));
+                        1
+                    }
+                },
+                Err(err) => {
+                    eprintln!("{}", err);
+                    1
+                }
+            }
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            2
+        }
+    }
+}
+
+fn load_graph(path: &str) -> Result<SaveFile, Error> {
+    let text = std::fs::read_to_string(path).map_err(|err| Error::Open(err.to_string()))?;
+    SaveFile::from_ron(&text).map_err(Error::Open)
+}
+
+fn print_graph(path: &str) -> Result<String, Error> {
+    let file = load_graph(path)?;
+    let start_block = StartBlock {
+        start_block: file.start_block,
+    };
+    Ok(sexpr::print_blocks(&file.to_block_list(), &start_block)?)
+}
+
+fn read_program(path: &str) -> Result<AstNode, Error> {
+    if path.ends_with(".ron") {
+        let file = load_graph(path)?;
+        Ok(file.to_block_list().parse(file.start_block)?)
+    } else {
+        let text = std::fs::read_to_string(path).map_err(|err| Error::Open(err.to_string()))?;
+        Ok(sexpr::read(&text)?)
+    }
+}
+
+// どの形式のファイルでも実行できるプログラムにする
+fn load_program(path: &str) -> Result<Program, Error> {
+    if path.ends_with(".bin") {
+        let bytes = std::fs::read(path).map_err(|err| Error::Open(err.to_string()))?;
+        return Program::from_bytes(&bytes).map_err(Error::Load);
+    }
+    if path.ends_with(".asm") {
+        let text = std::fs::read_to_string(path).map_err(|err| Error::Open(err.to_string()))?;
+        let (code, constants) = assemble(&text).map_err(Error::Assemble)?;
+        return Ok(Program {
+            entry: 0,
+            code,
+            constants,
+            debug: None,
+        });
+    }
+    let code = read_program(path)?;
+    let mut environment = Environment::default();
+    let (program, _) = code.compile_program(&mut environment)?;
+    Ok(program)
+}
+
+// 実行や書き出しの前に検証する　disasmは壊れたプログラムも見られるように検証しない
+fn load_verified_program(path: &str) -> Result<Program, Error> {
+    let program = load_program(path)?;
+    verify(&program).map_err(Error::Verify)?;
+    Ok(program)
+}
@@ -1,17 +1,31 @@
 use bevy::{
+    ecs::system::SystemParam,
     input::mouse::{MouseMotion, MouseWheel},
     prelude::*,
     ui::widget::NodeImageMode,
     window::PrimaryWindow,
 };
-use bevy_simple_text_input::{TextInput, TextInputPlugin, TextInputSubmitEvent, TextInputSystem};
+use bevy_simple_text_input::{
+    TextInput, TextInputInactive, TextInputPlugin, TextInputSubmitEvent, TextInputSystem,
+};
+use block::compiler::Error;
 use block::StartBlock;
 use rand::Rng;
 use std::f64::consts::PI;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
 mod block;
+mod cli;
 use block::*;
 
 fn main() {
+    // 引数があればウィンドウを開かずにコマンドラインで動かす
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args));
+    }
+
     App::new()
         .add_plugins(DefaultPlugins) // Bevyのデフォルトプラグインを追加
         .add_plugins(TextInputPlugin) // テキストプラグインを追加
@@ -21,6 +35,17 @@ fn main() {
         .add_systems(Startup, spawn_value_fields) // Identifier、数値召喚用テキストインプットを追加
         .add_systems(Startup, add_run_button) // 実行ボタン追加
         .add_systems(Update, run_button_click) // 実行ボタンイベント
+        .add_systems(Update, stop_button_click) // 停止ボタンイベント
+        .add_systems(Update, receive_program_output) // 実行中のプログラムの出力を表示
+        .add_systems(Update, highlight_error_block) // エラーを起こしたブロックを示す
+        .add_systems(Update, update_type_labels) // 推論した型をブロックのラベルに書く
+        .add_systems(Startup, add_graph_buttons) // 保存・読み込み・貼り付けボタン追加
+        .add_systems(Update, graph_button_click) // 保存・読み込み・貼り付けボタンイベント
+        .add_systems(Update, graph_shortcuts) // Ctrl+S、Ctrl+O、Ctrl+V
+        .add_systems(Update, focus_text_input.before(TextInputSystem)) // クリックした入力欄だけに入力する
+        .add_systems(Update, save_or_open_graph) // ファイルへの保存・読み込み
+        .add_systems(Update, paste_code) // クリップボードのS式をブロックにする
+        .add_event::<GraphAction>()
         .add_systems(Update, move_camera) // マウス操作を登録
         .add_systems(Update, show_menu) // ブロック配置
         .add_systems(Update, menu_search.after(TextInputSystem)) // テキストインプットイベント
@@ -32,6 +57,12 @@ fn main() {
         .insert_resource(block::BlockList::default()) // 出されたブロックのリストを追加
         .insert_resource(block::StartBlock::default()) // スタート位置指定
         .insert_resource(block::compiler::Environment::default()) // 環境
+        .insert_resource(block::compiler::VmOptions::default()) // 命令数の上限と停止フラグ
+        .insert_resource(RunningProgram::default()) // 別スレッドで実行中のプログラム
+        .insert_resource(Disassembly::default()) // 最後にコンパイルしたバイトコードの逆アセンブル
+        .insert_resource(ErrorHighlight::default()) // Runが失敗したときのブロック
+        .add_systems(Update, disassembly_button_click) // 逆アセンブル表示の切り替え
+        .add_systems(Update, show_disassembly) // 逆アセンブル表示
         .add_systems(Update, drag_system) // ドラッグできるようにする
         .run();
 }
@@ -93,6 +124,10 @@ fn setup(
             text: String::from("if"),
             block_type: block::BlockType::Statement,
         },
+        block::BlockData {
+            text: String::from("while"),
+            block_type: block::BlockType::Statement,
+        },
         block::BlockData {
             text: String::from("addf"),
             block_type: block::BlockType::Identifier,
@@ -117,6 +152,110 @@ fn setup(
             text: String::from("printf"),
             block_type: block::BlockType::Identifier,
         },
+        block::BlockData {
+            text: String::from("eqi"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("nei"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("lti"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("gti"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("lei"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("gei"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("eqf"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("nef"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("ltf"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("gtf"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("lef"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("gef"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("and"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("or"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("not"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("true"),
+            block_type: block::BlockType::Value,
+        },
+        block::BlockData {
+            text: String::from("false"),
+            block_type: block::BlockType::Value,
+        },
+        block::BlockData {
+            text: String::from("prints"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("concat"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("length"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("substring"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("eqs"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("make-array"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("get"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("set"),
+            block_type: block::BlockType::Identifier,
+        },
+        block::BlockData {
+            text: String::from("push"),
+            block_type: block::BlockType::Identifier,
+        },
     ];
 
     let id = spawn_block(
@@ -214,6 +353,7 @@ fn spawn_value_fields(mut commands: Commands) {
         },
         BackgroundColor::from(Color::srgba(0.2, 0.2, 0.2, 0.9)),
         TextInput,
+        TextInputInactive(true), // クリックするまでキー入力を受け取らない
         ValueInput,
     ));
 }
@@ -221,9 +361,87 @@ fn spawn_value_fields(mut commands: Commands) {
 #[derive(Component)]
 struct RunButton;
 
+#[derive(Component)]
+struct StopButton;
+
 #[derive(Component)]
 struct ResultText;
 
+#[derive(Component)]
+struct DisassemblyButton;
+
+#[derive(Component)]
+struct DisassemblyPanel;
+
+#[derive(Resource, Default)]
+struct Disassembly {
+    text: String,
+    visible: bool,
+}
+
+// Runが失敗したときに枠で示すブロックとエラーメッセージ
+#[derive(Resource, Default)]
+struct ErrorHighlight {
+    blocks: Vec<(u32, String)>,
+}
+
+impl ErrorHighlight {
+    // エラーを起こしたブロックを覚えて、結果表示欄に出すメッセージを返す
+    fn show(&mut self, err: Error) -> String {
+        self.blocks = err.blocks();
+        err.to_string()
+    }
+}
+
+// エラーのブロックに付ける赤い枠　メッセージはその子になる
+#[derive(Component)]
+struct ErrorOutline;
+
+// ブロックの上の小さいラベル　idはそのブロック
+#[derive(Component)]
+struct TypeLabel {
+    id: u32,
+}
+
+enum VmEvent {
+    Output(String),
+    Finished(Result<(), block::compiler::VmError>),
+}
+
+// ワーカースレッドから出力を受け取るチャンネル
+#[derive(Resource, Default)]
+struct RunningProgram {
+    receiver: Option<Mutex<Receiver<VmEvent>>>,
+}
+
+// Runで書き換える実行まわりのリソース
+#[derive(SystemParam)]
+struct RunState<'w> {
+    environment: ResMut<'w, block::compiler::Environment>,
+    vm_options: ResMut<'w, block::compiler::VmOptions>,
+    running_program: ResMut<'w, RunningProgram>,
+    disassembly: ResMut<'w, Disassembly>,
+    error_highlight: ResMut<'w, ErrorHighlight>,
+}
+
+// 読み込みや貼り付けで作り直すグラフ
+#[derive(SystemParam)]
+struct Graph<'w> {
+    block_list: ResMut<'w, block::BlockList>,
+    start_block: ResMut<'w, block::StartBlock>,
+}
+
+// グラフを作り直すときに消すブロックと線
+type GraphEntities<'w, 's> = Query<'w, 's, Entity, Or<(With<Draggable>, With<block::Line>)>>;
+
+// Tの付いたボタンの押下状態と背景色
+type ButtonInteraction<'w, 's, T> = Query<
+    'w,
+    's,
+    (&'static Interaction, &'static mut BackgroundColor),
+    (Changed<Interaction>, With<T>),
+>;
+
 fn add_run_button(mut commands: Commands, asset_server: Res<AssetServer>) {
     commands.spawn((
         Button,
@@ -243,81 +461,615 @@ fn add_run_button(mut commands: Commands, asset_server: Res<AssetServer>) {
         },
         RunButton,
     ));
+    commands
+        .spawn((
+            Button,
+            Node {
+                width: Val::Px(80.0),
+                height: Val::Px(30.0),
+                top: Val::Px(25.0),
+                right: Val::Percent(23.0),
+                margin: UiRect::right(Val::Px(90.0)),
+                position_type: PositionType::Absolute,
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..Default::default()
+            },
+            BackgroundColor(Color::srgba(0.8, 0.2, 0.2, 0.8)),
+            StopButton,
+        ))
+        .with_child((
+            Text::new("STOP"),
+            TextFont {
+                font: asset_server.load("fonts/FiraCode-Medium.ttf"),
+                font_size: 15.0,
+                ..default()
+            },
+            TextColor(Color::srgb(0.9, 0.9, 0.9)),
+        ));
+    commands
+        .spawn((
+            Button,
+            Node {
+                width: Val::Px(80.0),
+                height: Val::Px(30.0),
+                top: Val::Px(25.0),
+                right: Val::Percent(23.0),
+                margin: UiRect::right(Val::Px(450.0)),
+                position_type: PositionType::Absolute,
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..Default::default()
+            },
+            BackgroundColor(Color::srgba(0.2, 0.2, 0.2, 0.8)),
+            DisassemblyButton,
+        ))
+        .with_child((
+            Text::new("ASM"),
+            TextFont {
+                font: asset_server.load("fonts/FiraCode-Medium.ttf"),
+                font_size: 15.0,
+                ..default()
+            },
+            TextColor(Color::srgb(0.9, 0.9, 0.9)),
+        ));
+}
+
+fn stop_button_click(
+    mut interaction_query: ButtonInteraction<StopButton>,
+    vm_options: Res<block::compiler::VmOptions>,
+) {
+    for (interaction, mut color) in &mut interaction_query {
+        match *interaction {
+            Interaction::Pressed => {
+                *color = Color::srgba(1.0, 0.4, 0.4, 0.9).into();
+                vm_options.cancel.store(true, Ordering::Relaxed);
+            }
+            Interaction::Hovered => {
+                *color = Color::srgba(0.9, 0.3, 0.3, 0.8).into();
+            }
+            Interaction::None => {
+                *color = Color::srgba(0.8, 0.2, 0.2, 0.8).into();
+            }
+        };
+    }
+}
+
+fn disassembly_button_click(
+    mut interaction_query: ButtonInteraction<DisassemblyButton>,
+    mut disassembly: ResMut<Disassembly>,
+) {
+    for (interaction, mut color) in &mut interaction_query {
+        match *interaction {
+            Interaction::Pressed => {
+                *color = Color::srgba(0.6, 0.6, 0.6, 0.9).into();
+                disassembly.visible = !disassembly.visible;
+            }
+            Interaction::Hovered => {
+                *color = Color::srgba(0.4, 0.4, 0.4, 0.8).into();
+            }
+            Interaction::None => {
+                *color = Color::srgba(0.2, 0.2, 0.2, 0.8).into();
+            }
+        };
+    }
+}
+
+// 表示中の逆アセンブルをDisassemblyに合わせて作り直す
+fn show_disassembly(
+    mut commands: Commands,
+    disassembly: Res<Disassembly>,
+    panels: Query<Entity, With<DisassemblyPanel>>,
+    asset_server: Res<AssetServer>,
+) {
+    if !disassembly.is_changed() {
+        return;
+    }
+    for panel in panels.iter() {
+        commands.entity(panel).despawn_recursive();
+    }
+    if !disassembly.visible {
+        return;
+    }
+    commands
+        .spawn((
+            Node {
+                width: Val::Px(320.0),
+                max_height: Val::Percent(80.0),
+                top: Val::Px(70.0),
+                left: Val::Px(0.0),
+                position_type: PositionType::Absolute,
+                padding: UiRect::all(Val::Px(10.0)),
+                overflow: Overflow::clip(),
+                ..Default::default()
+            },
+            BackgroundColor(Color::srgba(0.1, 0.1, 0.1, 0.9)),
+            DisassemblyPanel,
+        ))
+        .with_child((
+            Text::new(if disassembly.text.is_empty() {
+                "(run a program to see its bytecode)".to_string()
+            } else {
+                disassembly.text.clone()
+            }),
+            TextFont {
+                font: asset_server.load("fonts/FiraCode-Medium.ttf"),
+                font_size: 12.0,
+                ..default()
+            },
+            TextColor(Color::srgb(0.9, 0.9, 0.9)),
+        ));
 }
 
 fn run_button_click(
     mut commands: Commands,
-    mut interaction_query: Query<
-        (&Interaction, &mut BackgroundColor),
-        (Changed<Interaction>, With<RunButton>),
-    >,
-    mut result_texts: Query<Entity, With<ResultText>>,
+    mut interaction_query: ButtonInteraction<RunButton>,
+    result_texts: Query<Entity, With<ResultText>>,
     block_list: Res<block::BlockList>,
     start_block: Res<block::StartBlock>,
     asset_server: Res<AssetServer>,
-    mut environment: ResMut<block::compiler::Environment>,
+    mut run_state: RunState,
 ) {
     for (interaction, mut color) in &mut interaction_query {
         match *interaction {
             Interaction::Pressed => {
                 *color = Color::srgba(0.8, 0.8, 0.8, 0.4).into();
                 println!("Compiling...");
-                environment.stack.clear();
-                let start_point_block = block_list.item[&start_block.start_block].1.clone();
-                let result = match start_point_block.parse(block_list.as_ref()) {
-                    Ok(code) => match code.compile(environment.as_mut(), true) {
-                        Ok((mut bytes, ret_type)) => {
-                            bytes.push(block::compiler::Opecodes::End as u8);
-                            for i in 0..bytes.len() {
-                                print!("{}:{:#X} ", i, bytes[i]);
-                            }
+                *run_state.error_highlight = ErrorHighlight::default();
+                // 前の実行が残っていれば止めて、新しい停止フラグを用意する
+                run_state.vm_options.cancel.store(true, Ordering::Relaxed);
+                run_state.vm_options.cancel = Arc::new(AtomicBool::new(false));
+                run_state.running_program.receiver = None;
+                run_state.environment.stack.clear();
+                let result = match block_list.parse(start_block.start_block) {
+                    Ok(code) => match code.compile_program(run_state.environment.as_mut()) {
+                        Ok((program, ret_type)) => {
+                            run_state.disassembly.text = block::compiler::disasm::disassemble(
+                                &program.code,
+                                &program.constants,
+                            );
                             println!("=> {}", ret_type);
 
-                            match block::compiler::execute_vm(bytes) {
-                                Ok(res) => res,
-                                Err(msg) => msg,
-                            }
-                        }
-                        Err(msg) => {
-                            format!("CompileError:{}", msg)
+                            let image = program.to_bytes();
+                            let options = run_state.vm_options.clone();
+                            let (sender, receiver) = channel();
+                            std::thread::spawn(move || {
+                                let result =
+                                    block::compiler::execute_vm(&image, &options, &mut |line| {
+                                        let _ = sender.send(VmEvent::Output(line));
+                                    });
+                                let _ = sender.send(VmEvent::Finished(result));
+                            });
+                            run_state.running_program.receiver = Some(Mutex::new(receiver));
+                            "".to_string()
                         }
+                        Err(err) => run_state.error_highlight.show(Error::Compile(err)),
                     },
-                    Err(msg) => {
-                        format!("ParseError:{}", msg)
-                    }
+                    Err(err) => run_state.error_highlight.show(Error::Parse(err)),
                 };
 
-                for text in result_texts.iter_mut() {
-                    commands.entity(text).despawn_recursive();
+                show_result(&mut commands, &result_texts, asset_server.as_ref(), result);
+            }
+            Interaction::Hovered => {
+                *color = Color::srgba(0.8, 0.8, 0.8, 0.2).into();
+            }
+            Interaction::None => {
+                *color = Color::srgba(0.0, 0.0, 0.0, 0.0).into();
+            }
+        };
+    }
+}
+
+// 結果表示欄を書き換える
+fn show_result(
+    commands: &mut Commands,
+    result_texts: &Query<Entity, With<ResultText>>,
+    asset_server: &AssetServer,
+    result: String,
+) {
+    for text in result_texts.iter() {
+        commands.entity(text).despawn_recursive();
+    }
+    commands.spawn((
+        Text::new(result),
+        TextFont {
+            font: asset_server.load("fonts/FiraCode-Medium.ttf"),
+            font_size: 20.0,
+            ..default()
+        },
+        TextColor(Color::srgb(0.9, 0.9, 0.9)),
+        Node {
+            top: Val::Px(100.0),
+            right: Val::Percent(23.0),
+            position_type: PositionType::Absolute,
+            border: UiRect::all(Val::Px(5.0)),
+            ..Default::default()
+        },
+        ResultText,
+    ));
+}
+
+fn receive_program_output(
+    mut running_program: ResMut<RunningProgram>,
+    mut result_texts: Query<&mut Text, With<ResultText>>,
+    mut error_highlight: ResMut<ErrorHighlight>,
+) {
+    let Some(receiver) = &running_program.receiver else {
+        return;
+    };
+    let mut lines = "".to_string();
+    let mut finished = false;
+    if let Ok(receiver) = receiver.lock() {
+        loop {
+            match receiver.try_recv() {
+                Ok(VmEvent::Output(line)) => lines += &format!("{}\n", line),
+                Ok(VmEvent::Finished(result)) => {
+                    if let Err(err) = result {
+                        lines += &error_highlight.show(Error::Runtime(err));
+                    }
+                    finished = true;
+                    break;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    finished = true;
+                    break;
                 }
-                commands.spawn((
-                    Text::new(result.clone()),
+            }
+        }
+    }
+    if finished {
+        running_program.receiver = None;
+    }
+    if lines.is_empty() {
+        return;
+    }
+    for mut text in result_texts.iter_mut() {
+        text.0 += &lines;
+    }
+}
+
+// エラーを起こしたブロックに点滅する赤い枠と、メッセージの吹き出しを付ける
+fn highlight_error_block(
+    mut commands: Commands,
+    error_highlight: Res<ErrorHighlight>,
+    block_list: Res<block::BlockList>,
+    asset_server: Res<AssetServer>,
+    time: Res<Time>,
+    mut outlines: Query<(Entity, &mut Sprite), With<ErrorOutline>>,
+) {
+    if error_highlight.is_changed() {
+        for (entity, _) in outlines.iter() {
+            commands.entity(entity).despawn_recursive();
+        }
+        // 同じブロックのエラーは一つの吹き出しにまとめる
+        let mut messages: Vec<(u32, Vec<&str>)> = vec![];
+        for (id, message) in error_highlight.blocks.iter() {
+            match messages.iter_mut().find(|(block, _)| block == id) {
+                Some((_, lines)) => lines.push(message),
+                None => messages.push((*id, vec![message])),
+            }
+        }
+        for (id, lines) in messages {
+            let Some((entity, block)) = block_list.item.get(&id) else {
+                continue;
+            };
+            spawn_error_outline(&mut commands, *entity, block, &lines, asset_server.as_ref());
+        }
+    }
+    for (_, mut sprite) in outlines.iter_mut() {
+        sprite
+            .color
+            .set_alpha(0.6 + 0.4 * (time.elapsed_secs() * 6.0).sin());
+    }
+}
+
+// ブロックに赤い枠を付けて、その下にメッセージを出す
+fn spawn_error_outline(
+    commands: &mut Commands,
+    entity: Entity,
+    block: &Block,
+    lines: &[&str],
+    asset_server: &AssetServer,
+) {
+    let message = lines.join("\n");
+    let message_width = lines.iter().map(|line| line.len()).max().unwrap_or(0);
+    let width = block.data.text.len() as f32 * 15.0;
+    let outline = commands
+        .spawn((
+            Sprite {
+                color: Color::srgb(1.0, 0.0, 0.0),
+                custom_size: Some(Vec2::new(width + 12.0, 32.0)),
+                ..Default::default()
+            },
+            Transform::from_xyz(0.0, 0.0, -11.0),
+            ErrorOutline,
+        ))
+        .with_children(|outline| {
+            outline
+                .spawn((
+                    Text2d::new(message),
+                    TextColor(Color::srgb(1.0, 0.4, 0.4)),
                     TextFont {
                         font: asset_server.load("fonts/FiraCode-Medium.ttf"),
-                        font_size: 20.0,
-                        ..default()
+                        font_size: 12.0,
+                        ..Default::default()
                     },
-                    TextColor(Color::srgb(0.9, 0.9, 0.9)),
-                    Node {
-                        top: Val::Px(100.0),
-                        right: Val::Percent(23.0),
-                        position_type: PositionType::Absolute,
-                        border: UiRect::all(Val::Px(5.0)),
+                    Transform::from_xyz(0.0, -28.0, 13.0),
+                ))
+                .with_child((
+                    Sprite {
+                        color: Color::srgba(0.0, 0.0, 0.0, 0.8),
+                        custom_size: Some(Vec2::new(
+                            message_width as f32 * 7.5 + 10.0,
+                            lines.len() as f32 * 15.0 + 3.0,
+                        )),
                         ..Default::default()
                     },
-                    ResultText,
+                    Transform::from_xyz(0.0, 0.0, -1.0),
                 ));
+        })
+        .id();
+    commands.entity(entity).add_child(outline);
+}
+
+#[derive(Event, Clone, Copy)] // EventはComponentでもあるのでボタンにも付けられる
+enum GraphAction {
+    Save,
+    Open,
+    Paste,
+}
+
+fn add_graph_buttons(mut commands: Commands, asset_server: Res<AssetServer>) {
+    for (label, action, margin) in [
+        ("SAVE", GraphAction::Save, 180.0),
+        ("OPEN", GraphAction::Open, 270.0),
+        ("PASTE", GraphAction::Paste, 360.0),
+    ] {
+        commands
+            .spawn((
+                Button,
+                Node {
+                    width: Val::Px(80.0),
+                    height: Val::Px(30.0),
+                    top: Val::Px(25.0),
+                    right: Val::Percent(23.0),
+                    margin: UiRect::right(Val::Px(margin)),
+                    position_type: PositionType::Absolute,
+                    justify_content: JustifyContent::Center,
+                    align_items: AlignItems::Center,
+                    ..Default::default()
+                },
+                BackgroundColor(Color::srgba(0.2, 0.2, 0.2, 0.8)),
+                action,
+            ))
+            .with_child((
+                Text::new(label),
+                TextFont {
+                    font: asset_server.load("fonts/FiraCode-Medium.ttf"),
+                    font_size: 15.0,
+                    ..default()
+                },
+                TextColor(Color::srgb(0.9, 0.9, 0.9)),
+            ));
+    }
+}
+
+fn graph_button_click(
+    mut interaction_query: Query<
+        (&Interaction, &mut BackgroundColor, &GraphAction),
+        Changed<Interaction>,
+    >,
+    mut actions: EventWriter<GraphAction>,
+) {
+    for (interaction, mut color, action) in &mut interaction_query {
+        match *interaction {
+            Interaction::Pressed => {
+                *color = Color::srgba(0.6, 0.6, 0.6, 0.9).into();
+                actions.send(*action);
             }
             Interaction::Hovered => {
-                *color = Color::srgba(0.8, 0.8, 0.8, 0.2).into();
+                *color = Color::srgba(0.4, 0.4, 0.4, 0.8).into();
             }
             Interaction::None => {
-                *color = Color::srgba(0.0, 0.0, 0.0, 0.0).into();
+                *color = Color::srgba(0.2, 0.2, 0.2, 0.8).into();
             }
         };
     }
 }
 
+// 左クリックした入力欄を有効にして、他の入力欄は無効にする
+fn focus_text_input(
+    mouse_button: Res<ButtonInput<MouseButton>>,
+    mut inputs: Query<(&Interaction, &mut TextInputInactive)>,
+) {
+    if !mouse_button.just_pressed(MouseButton::Left) {
+        return;
+    }
+    for (interaction, mut inactive) in &mut inputs {
+        inactive.0 = *interaction != Interaction::Pressed;
+    }
+}
+
+fn graph_shortcuts(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    inputs: Query<&TextInputInactive>,
+    mut actions: EventWriter<GraphAction>,
+) {
+    // 入力欄に文字を打っている間は入力欄の貼り付けなどを優先する
+    if inputs.iter().any(|inactive| !inactive.0) {
+        return;
+    }
+    if !keyboard.any_pressed([
+        KeyCode::ControlLeft,
+        KeyCode::ControlRight,
+        KeyCode::SuperLeft,
+        KeyCode::SuperRight,
+    ]) {
+        return;
+    }
+    if keyboard.just_pressed(KeyCode::KeyS) {
+        actions.send(GraphAction::Save);
+    }
+    if keyboard.just_pressed(KeyCode::KeyO) {
+        actions.send(GraphAction::Open);
+    }
+    if keyboard.just_pressed(KeyCode::KeyV) {
+        actions.send(GraphAction::Paste);
+    }
+}
+
+fn save_or_open_graph(
+    mut commands: Commands,
+    mut actions: EventReader<GraphAction>,
+    mut graph: Graph,
+    mut drag_state: ResMut<block::DragState>,
+    asset_server: Res<AssetServer>,
+    entities: GraphEntities,
+    result_texts: Query<Entity, With<ResultText>>,
+) {
+    for action in actions.read() {
+        let result = match action {
+            GraphAction::Save => save::SaveFile::new(&graph.block_list, &graph.start_block)
+                .to_ron()
+                .and_then(|text| {
+                    std::fs::write(save::DEFAULT_SAVE_PATH, text).map_err(|err| err.to_string())
+                })
+                .map(|_| format!("Saved to {}", save::DEFAULT_SAVE_PATH))
+                .unwrap_or_else(|msg| format!("SaveError:{}", msg)),
+            GraphAction::Open => match std::fs::read_to_string(save::DEFAULT_SAVE_PATH)
+                .map_err(|err| err.to_string())
+                .and_then(|text| save::SaveFile::from_ron(&text))
+            {
+                Ok(file) => {
+                    // 今のグラフを消してから、ブロック、線の順に作り直す
+                    for entity in entities.iter() {
+                        commands.entity(entity).despawn_recursive();
+                    }
+                    graph.block_list.item.clear();
+                    *drag_state = block::DragState::default();
+                    for saved in file.blocks.iter() {
+                        spawn_block_with_id(
+                            &mut commands,
+                            saved.to_block(),
+                            saved.id,
+                            asset_server.as_ref(),
+                            graph.block_list.as_mut(),
+                        );
+                    }
+                    for saved in file.blocks.iter() {
+                        for (index, input) in saved.inputs.iter().enumerate() {
+                            let start = graph.block_list.item[input].1.position;
+                            let end = graph.block_list.item[&saved.id].1.position;
+                            spawn_line_entity(
+                                &mut commands,
+                                block::Line {
+                                    start: *input,
+                                    end: saved.id,
+                                    label: (index + 1).to_string(),
+                                },
+                                asset_server.as_ref(),
+                                start.extend(0.0),
+                                end.extend(0.0),
+                            );
+                        }
+                    }
+                    graph.start_block.start_block = file.start_block;
+                    format!("Opened {}", save::DEFAULT_SAVE_PATH)
+                }
+                Err(msg) => format!("OpenError:{}", msg),
+            },
+            GraphAction::Paste => continue,
+        };
+        show_result(&mut commands, &result_texts, asset_server.as_ref(), result);
+    }
+}
+
+fn paste_code(
+    mut commands: Commands,
+    mut actions: EventReader<GraphAction>,
+    mut graph: Graph,
+    asset_server: Res<AssetServer>,
+    window_query: Query<&Window, With<PrimaryWindow>>,
+    camera_query: Query<(&Camera, &GlobalTransform)>,
+    result_texts: Query<Entity, With<ResultText>>,
+) {
+    for action in actions.read() {
+        if !matches!(action, GraphAction::Paste) {
+            continue;
+        }
+        let (camera, camera_transform) = camera_query.single();
+        let window = window_query.single();
+        let center = Vec2::new(window.width() / 2.0, window.height() / 2.0);
+        let result = match arboard::Clipboard::new()
+            .and_then(|mut clipboard| clipboard.get_text())
+            .map_err(|err| err.to_string())
+            .and_then(|text| sexpr::read(&text).map_err(|err| err.to_string()))
+        {
+            Ok(code) => {
+                let position = camera
+                    .viewport_to_world_2d(camera_transform, center)
+                    .unwrap_or_default();
+                // 貼り付けたプログラムをそのまま実行できるようにスタートにする
+                graph.start_block.start_block = spawn_ast(
+                    &mut commands,
+                    &code,
+                    position,
+                    asset_server.as_ref(),
+                    graph.block_list.as_mut(),
+                );
+                "Pasted code as the new start block".to_string()
+            }
+            Err(msg) => format!("PasteError:{}", msg),
+        };
+        show_result(&mut commands, &result_texts, asset_server.as_ref(), result);
+    }
+}
+
+// ノードとその子孫をブロックにして線でつなぐ　子は一段下に左から並べる
+fn spawn_ast(
+    commands: &mut Commands,
+    node: &block::compiler::AstNode,
+    position: Vec2,
+    asset_server: &AssetServer,
+    block_list: &mut BlockList,
+) -> u32 {
+    let (data, children) = sexpr::to_block_data(node);
+    let positions = sexpr::children_positions(node, position);
+    let inputs: Vec<u32> = children
+        .iter()
+        .zip(positions.iter())
+        .map(|(child, child_position)| {
+            spawn_ast(commands, child, *child_position, asset_server, block_list)
+        })
+        .collect();
+    let id = spawn_block(
+        commands,
+        block::Block {
+            data,
+            position,
+            inputs: inputs.clone(),
+            comment: "".to_string(),
+        },
+        asset_server,
+        block_list,
+    );
+    for (index, (input, child_position)) in inputs.iter().zip(positions).enumerate() {
+        spawn_line_entity(
+            commands,
+            block::Line {
+                start: *input,
+                end: id,
+                label: (index + 1).to_string(),
+            },
+            asset_server,
+            child_position.extend(0.0),
+            position.extend(0.0),
+        );
+    }
+    id
+}
+
 fn add_value(
     mut commands: Commands,
     mut events: EventReader<TextInputSubmitEvent>,
@@ -339,7 +1091,33 @@ fn add_value(
         }
 
         let (camera, camera_transform) = camera_query.single();
+        // "で囲まれた入力は文字列の値ブロックにする
+        let is_string =
+            event.value.len() >= 2 && event.value.starts_with('"') && event.value.ends_with('"');
         match event.value.parse::<f64>() {
+            Err(_) if is_string => {
+                let position = Vec2::new(
+                    window_query.single().width() / 2.0,
+                    window_query.single().height() / 2.0,
+                );
+                if let Ok(world_position) = camera.viewport_to_world_2d(camera_transform, position)
+                {
+                    spawn_block(
+                        &mut commands,
+                        block::Block {
+                            data: block::BlockData {
+                                text: event.value.clone(),
+                                block_type: block::BlockType::Value,
+                            },
+                            position: world_position,
+                            inputs: vec![],
+                            comment: "".to_string(),
+                        },
+                        asset_server.as_ref(),
+                        block_list.as_mut(),
+                    );
+                }
+            }
             Ok(num) => {
                 let position = Vec2::new(
                     window_query.single().width() / 2.0,
@@ -636,12 +1414,88 @@ fn spawn_block_button(
     }
 }
 
+// グラフが変わるたびにスタートから辿れるブロックの型を推論して、ラベルに書く
+// 型の食い違いなどのエラーを起こしたブロックのラベルは赤くする
+fn update_type_labels(
+    block_list: Res<BlockList>,
+    start_block: Res<StartBlock>,
+    mut labels: Query<(&TypeLabel, &mut Text2d, &mut TextColor)>,
+    added: Query<(), Added<TypeLabel>>,
+) {
+    // 読み込みや貼り付けのブロックは、出たあとのフレームでラベルができる
+    if !block_list.is_changed() && !start_block.is_changed() && added.is_empty() {
+        return;
+    }
+    // 繋ぎかけでパースできないときは型を出さない
+    let inference = block_list
+        .parse(start_block.start_block)
+        .ok()
+        .map(|node| block::compiler::infer::infer(&node));
+    for (label, mut text, mut color) in &mut labels {
+        let Some((_, block)) = block_list.item.get(&label.id) else {
+            continue;
+        };
+        let (inferred, has_error) = match &inference {
+            Some(inference) => (
+                inference.types.get(&label.id).map(|ty| ty.to_string()),
+                inference
+                    .errors
+                    .iter()
+                    .any(|err| err.block == Some(label.id)),
+            ),
+            None => (None, false),
+        };
+        text.0 = block_label(block, inferred);
+        color.0 = if has_error {
+            Color::srgb(1.0, 0.2, 0.2)
+        } else {
+            Color::srgb(1.0, 1.0, 1.0)
+        };
+    }
+}
+
+// ブロックの上に出す小さいラベル　種類と、推論した型か型注釈の型と、コメント
+fn block_label(block: &Block, inferred: Option<String>) -> String {
+    let kind = match block.data.block_type {
+        BlockType::Statement => "statement",
+        BlockType::Value => "value",
+        BlockType::List => "list",
+        BlockType::Identifier => "identifier",
+    };
+    let annotation = match block.data.block_type {
+        BlockType::Identifier => match block::compiler::split_annotation(&block.data.text) {
+            Ok((_, t)) if t != block::compiler::Type::Unknown => Some(t.to_string()),
+            _ => None,
+        },
+        _ => None,
+    };
+    match inferred.or(annotation) {
+        Some(t) => format!("{} : {} : {}", kind, t, block.comment),
+        None => format!("{} : {}", kind, block.comment),
+    }
+}
+
 pub fn spawn_block(
     commands: &mut Commands,
     block: Block,
     asset_server: &AssetServer,
     block_list: &mut BlockList,
 ) -> u32 {
+    let mut rng = rand::thread_rng();
+    let random_id: u32 = rng.gen_range(1..=u32::MAX); // 0を除く
+    spawn_block_with_id(commands, block, random_id, asset_server, block_list);
+
+    random_id
+}
+
+// ファイルから読み込むときは保存されていたidをそのまま使う
+pub fn spawn_block_with_id(
+    commands: &mut Commands,
+    block: Block,
+    id: u32,
+    asset_server: &AssetServer,
+    block_list: &mut BlockList,
+) {
     let text_entity = commands
         .spawn((
             Text2d::new(String::from(block.data.text.clone())),
@@ -656,15 +1510,7 @@ pub fn spawn_block(
         .id();
     let typetext_entity = commands // it also adds comment
         .spawn((
-            Text2d::new(
-                String::from(match block.data.block_type {
-                    BlockType::Statement => "statement",
-                    BlockType::Value => "value",
-                    BlockType::List => "list",
-                    BlockType::Identifier => "identifier",
-                }) + " : "
-                    + &block.comment,
-            ),
+            Text2d::new(block_label(&block, None)),
             TextColor(Color::srgb(1.0, 1.0, 1.0)),
             TextFont {
                 font: asset_server.load("fonts/FiraCode-Medium.ttf"),
@@ -676,6 +1522,7 @@ pub fn spawn_block(
                 15.0,
                 1.0,
             ),
+            TypeLabel { id },
         ))
         .id();
     let shadow_entity = commands
@@ -691,8 +1538,6 @@ pub fn spawn_block(
             Transform::from_xyz(0.0, 0.0, -10.0),
         ))
         .id();
-    let mut rng = rand::thread_rng();
-    let random_id: u32 = rng.gen_range(1..=u32::MAX); // 0を除く
     let block_entity = commands
         .spawn((
             Sprite {
@@ -706,15 +1551,13 @@ pub fn spawn_block(
                 ..Default::default()
             },
             Transform::from_xyz(block.position.x, block.position.y, 0.0),
-            Draggable { id: random_id },
+            Draggable { id },
         ))
         .add_child(text_entity)
         .add_child(typetext_entity)
         .add_child(shadow_entity)
         .id();
-    block_list.item.insert(random_id, (block_entity, block));
-
-    random_id
+    block_list.item.insert(id, (block_entity, block));
 }
 
 pub fn drag_system(
@@ -795,10 +1638,14 @@ pub fn drag_system(
                 && keyboard.pressed(KeyCode::ShiftLeft)
             {
                 if let Some(entity) = drag_state.dragged_entity {
-                    if let Ok((_, mut transform, _)) = sprites.get_mut(entity) {
+                    if let Ok((_, mut transform, draggable)) = sprites.get_mut(entity) {
                         // エンティティの位置を更新
                         transform.translation.x = world_position.x;
                         transform.translation.y = world_position.y;
+                        // 保存するときのためにブロックの位置も更新
+                        if let Some((_, block)) = block_list.item.get_mut(&draggable.id) {
+                            block.position = world_position;
+                        }
                     }
                 }
             }
@@ -822,92 +1669,107 @@ pub fn spawn_line(
         block_query.get(block_list.item[&line.start].0),
         block_query.get(block_list.item[&line.end].0),
     ) {
-        let start = start.translation;
-        let end = end.translation;
-        let text_entity = commands
-            .spawn((
-                Text2d::new(String::from(line.label.clone())),
-                TextColor(Color::srgb(1.0, 1.0, 1.0)),
-                TextFont {
-                    font: asset_server.load("fonts/FiraCode-Medium.ttf"),
-                    font_size: 20.0,
-                    ..Default::default()
-                },
-                Transform {
-                    translation: Vec3::new(0.0, -13.0, -100.0),
-                    rotation: Quat::from_rotation_z(PI as f32),
-                    ..Default::default()
-                },
-            ))
-            .id();
-
-        // 矢印
-        let arrow_entity1 = commands
-            .spawn((
-                Sprite {
-                    color: Color::WHITE,
-                    custom_size: Some(Vec2::new(10.0, 2.0)),
-                    ..Default::default()
-                },
-                Transform {
-                    translation: Vec3::new(0.0, 3.0, -100.0),
-                    rotation: Quat::from_rotation_z(-PI as f32 / 6.0),
-                    ..Default::default()
-                },
-            ))
-            .id();
-        // 矢印
-        let arrow_entity2 = commands
-            .spawn((
-                Sprite {
-                    color: Color::WHITE,
-                    custom_size: Some(Vec2::new(10.0, 2.0)),
-                    ..Default::default()
-                },
-                Transform {
-                    translation: Vec3::new(0.0, -3.0, -100.0),
-                    rotation: Quat::from_rotation_z(PI as f32 / 6.0),
-                    ..Default::default()
-                },
-            ))
-            .id();
-
-        // 線の長さと角度を計算
-        let difference = end - start;
-        let length = difference.length();
-        let rotation = difference.y.atan2(difference.x);
-
-        // 線をSpriteとして生成
-        commands
-            .spawn((
-                Sprite {
-                    color: Color::WHITE,
-                    custom_size: Some(Vec2::new(length, 2.0)), // 長さと太さ
-                    ..default()
-                },
-                Transform {
-                    translation: Vec3::new(
-                        start.x + difference.x / 2.0, // 中点のx座標
-                        start.y + difference.y / 2.0, // 中点のy座標
-                        -100.0,
-                    ),
-                    rotation: Quat::from_rotation_z(rotation),
-                    ..default()
-                },
-                Line {
-                    start: line.start,
-                    end: line.end,
-                    label: line.label.clone(),
-                },
-            ))
-            .add_child(text_entity)
-            .add_child(arrow_entity1)
-            .add_child(arrow_entity2);
+        spawn_line_entity(
+            commands,
+            line,
+            asset_server,
+            start.translation,
+            end.translation,
+        );
     } else {
         println!("failed to connect blocks.")
     };
 }
 
+// 線の位置はconnect_blocksが毎フレーム両端のブロックに合わせて更新する
+pub fn spawn_line_entity(
+    commands: &mut Commands,
+    line: Line,
+    asset_server: &AssetServer,
+    start: Vec3,
+    end: Vec3,
+) {
+    let text_entity = commands
+        .spawn((
+            Text2d::new(String::from(line.label.clone())),
+            TextColor(Color::srgb(1.0, 1.0, 1.0)),
+            TextFont {
+                font: asset_server.load("fonts/FiraCode-Medium.ttf"),
+                font_size: 20.0,
+                ..Default::default()
+            },
+            Transform {
+                translation: Vec3::new(0.0, -13.0, -100.0),
+                rotation: Quat::from_rotation_z(PI as f32),
+                ..Default::default()
+            },
+        ))
+        .id();
+
+    // 矢印
+    let arrow_entity1 = commands
+        .spawn((
+            Sprite {
+                color: Color::WHITE,
+                custom_size: Some(Vec2::new(10.0, 2.0)),
+                ..Default::default()
+            },
+            Transform {
+                translation: Vec3::new(0.0, 3.0, -100.0),
+                rotation: Quat::from_rotation_z(-PI as f32 / 6.0),
+                ..Default::default()
+            },
+        ))
+        .id();
+    // 矢印
+    let arrow_entity2 = commands
+        .spawn((
+            Sprite {
+                color: Color::WHITE,
+                custom_size: Some(Vec2::new(10.0, 2.0)),
+                ..Default::default()
+            },
+            Transform {
+                translation: Vec3::new(0.0, -3.0, -100.0),
+                rotation: Quat::from_rotation_z(PI as f32 / 6.0),
+                ..Default::default()
+            },
+        ))
+        .id();
+
+    // 線の長さと角度を計算
+    let difference = end - start;
+    let length = difference.length();
+    let rotation = difference.y.atan2(difference.x);
+
+    // 線をSpriteとして生成
+    commands
+        .spawn((
+            Sprite {
+                color: Color::WHITE,
+                custom_size: Some(Vec2::new(length, 2.0)), // 長さと太さ
+                ..default()
+            },
+            Transform {
+                translation: Vec3::new(
+                    start.x + difference.x / 2.0, // 中点のx座標
+                    start.y + difference.y / 2.0, // 中点のy座標
+                    -100.0,
+                ),
+                rotation: Quat::from_rotation_z(rotation),
+                ..default()
+            },
+            Line {
+                start: line.start,
+                end: line.end,
+                label: line.label.clone(),
+            },
+        ))
+        .add_child(text_entity)
+        .add_child(arrow_entity1)
+        .add_child(arrow_entity2);
+}
+
 pub fn connect_blocks(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
@@ -946,6 +1808,13 @@ pub fn connect_blocks(
                                 START = draggable.id;
                             } else if START != END {
                                 END = draggable.id;
+                                // 入力の先にあるブロックにつなぐと循環してパースできなくなる
+                                if block_list.reaches(START, END) {
+                                    println!("cannot connect a block to its own input.");
+                                    START = 0;
+                                    END = 0;
+                                    break;
+                                }
                                 let line = Line {
                                     start: START,
                                     end: END,